@@ -4,17 +4,33 @@ use std::io::Error;
 use std::env;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use parfs::server::config::ServerConfig;
 use parfs::server::fsrw_mutex::FsrwMutex;
-use parfs::server::handler::ConnectionHandler;
+use parfs::server::handler::{reply_busy, ConnectionHandler};
+use parfs::server::locks::AdvisoryLocks;
+use parfs::server::sessions::Sessions;
+use parfs::server::storage::{LocalStorage, StorageBackend};
 use parfs::server::threadpool::ThreadPool;
-use parfs::server::utilities::MAX_REQUEST_NO;
+use parfs::server::trash::Trash;
+use parfs::server::usage::UsageLedger;
+use parfs::server::users::Users;
+use parfs::server::watch::ChangeNotifier;
+use parfs::server::utilities::{TRASH_PURGE_INTERVAL, WORKER_IDLE_TIMEOUT};
 
 fn main() {
   let args: Vec<String> =env::args().collect();
   let addr_to_listen: &str = &args[1];
   let home_folder: PathBuf = PathBuf::from(&args[2]);
-
-  let first_free_port: usize = 12801;
+  let config: ServerConfig = match ServerConfig::from_args(&args[3..]) {
+    Ok(config) => config,
+    Err(e) => {
+      println!("{}", e);
+      exit(1);
+    }
+  };
+  let config = Arc::new(config);
 
   let listener_result: Result<TcpListener, Error> = TcpListener::bind(addr_to_listen);
 
@@ -26,17 +42,69 @@ fn main() {
     exit(1);
   }
   
+  // Where the shared files are stored
+  let storage: Arc<dyn StorageBackend> = Arc::new(LocalStorage::new(&home_folder, config.dedup));
+
   // Initialize file system reader writer mutex
-  let fsrw_mutex = Arc::new(FsrwMutex::new());
+  let lock_timeout = if config.lock_timeout > 0 { Some(Duration::from_secs(config.lock_timeout)) } else { None };
+  let fsrw_mutex = Arc::new(FsrwMutex::with_timeout(storage.clone(), lock_timeout).with_os_locks(config.os_locks));
+
+  // Wakes up sessions watching a directory when another session changes something
+  let notifier = Arc::new(ChangeNotifier::new());
+
+  // Advisory locks that clients take on files
+  let locks = Arc::new(AdvisoryLocks::new());
+
+  // The connected clients, for admins to see what everyone is doing
+  let sessions = Arc::new(Sessions::new());
+
+  // Users that can log in, and who owns which file to measure their usage against their quotas
+  let users: Users = match &config.users_file {
+    Some(users_file) => match Users::load(users_file) {
+      Ok(users) => users,
+      Err(e) => {
+        println!("Error reading users: {}", e);
+        exit(1);
+      }
+    },
+    None => Users::none(),
+  };
+  let users = Arc::new(users);
+  let ledger = match UsageLedger::load(&home_folder, storage.clone()) {
+    Ok(ledger) => Arc::new(ledger),
+    Err(e) => {
+      println!("Error reading file owners: {}", e);
+      exit(1);
+    }
+  };
+
+  // Periodically purges entries that have been in the trash for too long
+  if config.trash_days > 0 {
+    let trash = Trash::new(&home_folder, storage.clone(), ledger.clone());
+    let max_age = Duration::from_secs(config.trash_days * 24 * 60 * 60);
+    thread::spawn(move || loop {
+      if let Err(e) = trash.purge_expired(max_age) {
+        println!("Purging the trash failed: {}", e);
+      }
+      thread::sleep(TRASH_PURGE_INTERVAL);
+    });
+  }
 
-  // Creates a threadpool
-  let ports: Vec<usize> = (first_free_port..(first_free_port + MAX_REQUEST_NO)).collect();
-  let threadpool = ThreadPool::new(ports);
+  // Creates a threadpool, each connection is handled by a worker on a port of its own
+  let threadpool = ThreadPool::new(config.min_workers, config.max_workers, WORKER_IDLE_TIMEOUT, config.queue_length);
 
   // Listens for incoming connection requests
   let listener: TcpListener = listener_result.unwrap();
   for stream in listener.incoming() {
     let stream = stream.unwrap();
+    // Kept to answer the client if it has to be turned away
+    let reply_stream = match stream.try_clone() {
+      Ok(reply_stream) => reply_stream,
+      Err(e) => {
+        println!("Error accepting connection: {}", e);
+        continue;
+      }
+    };
 
     // Create handler for incoming stream
     let handle = 
@@ -44,10 +112,22 @@ fn main() {
         stream, 
         home_folder.clone(), 
         fsrw_mutex.clone(), 
-        addr_to_listen.to_string()
+        addr_to_listen.to_string(),
+        config.clone(),
+        storage.clone(),
+        notifier.clone(),
+        locks.clone(),
+        users.clone(),
+        ledger.clone(),
+        sessions.clone(),
       ).unwrap();
 
     // Pass handler off to threadpool to initialise new ports and handle requests
-    threadpool.execute(|port: usize| {handle.handle_connection(port);});
+    if let Err((_, waiting)) = threadpool.execute(move || {handle.handle_connection();}) {
+      println!("Server busy: turning away a client with {} clients waiting", waiting);
+      if let Err(e) = reply_busy(&reply_stream, waiting, &fsrw_mutex, storage.as_ref()) {
+        println!("Error replying to a client that was turned away: {}", e);
+      }
+    }
   }
 }
@@ -1,12 +1,20 @@
 use crate::client::utilities::*;
 use regex::Regex;
-use std::io::{Error, Read};
+use std::fs;
+use std::io::{self, Error, Read};
 use std::net::TcpStream;
-use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use crate::client::errors::*;
-use crate::message::MessageKind;
+use crate::client::sync::{plan_sync, SyncAction, SyncMode};
+use crate::delta::{decode_signatures, write_delta};
+use crate::manifest::{decode_manifest, file_entry, list_tree, manifest_path, Manifest, ManifestEntry};
+use crate::message::{add_option, FileAttributes, MessageKind, PayloadAborted, BUFFER_SIZE, SERVER_BUSY_PORT};
+use crate::policy::{is_newer, OverwritePolicy, Resolution};
+use crate::utilities::encode_time;
 use crate::client::message::receiver::MessageReceiver;
 use crate::client::message::sender::MessageSender;
 
@@ -31,9 +39,22 @@ impl Connection {
             "mkdir" => Command::Mkdir,
             "cd" => Command::Cd,
             "ls" => Command::Ls,
+            "stat" => Command::Stat,
             "up" => Command::Up,
             "down" => Command::Down,
             "status" => Command::Status,
+            "versions" => Command::Versions,
+            "restore" => Command::Restore,
+            "rm" => Command::Rm,
+            "trash" => Command::Trash,
+            "sync" => Command::Sync,
+            "watch" => Command::Watch,
+            "lock" => Command::Lock,
+            "unlock" => Command::Unlock,
+            "quota" => Command::Quota,
+            "df" => Command::Df,
+            "locks" => Command::Locks,
+            "sessions" => Command::Sessions,
             _ => return Err(ClientError::InvalidCommand),
         };
 
@@ -64,9 +85,23 @@ impl Connection {
         match command_type {
             Command::Cd => self.cd(&tokens)?,
             Command::Ls => self.ls(&tokens)?,
+            Command::Stat => self.stat(&tokens)?,
             Command::Down => self.down(&tokens)?,
             Command::Up => self.up(&tokens)?,
             Command::Mkdir => self.mkdir(&tokens)?,
+            Command::Versions => self.versions(&tokens)?,
+            Command::Restore => self.restore(&tokens)?,
+            Command::Rm => self.rm(&tokens)?,
+            Command::Trash => self.trash(&tokens)?,
+            Command::Sync => self.sync(&tokens)?,
+            Command::Watch => self.watch(&tokens)?,
+            Command::Lock => self.lock(&tokens)?,
+            Command::Unlock => self.unlock(&tokens)?,
+            Command::Login => self.login(&tokens)?,
+            Command::Quota => self.quota(&tokens)?,
+            Command::Df => self.df(&tokens)?,
+            Command::Locks => self.locks(&tokens)?,
+            Command::Sessions => self.sessions(&tokens)?,
             _ => return Err(ClientError::InvalidCommand),
         }
 
@@ -110,6 +145,13 @@ impl Connection {
         let addr_split: Vec<&str> = addr.split(":").collect();
         let ip_addr = addr_split[0];
         let new_port: i32 = i32::from_le_bytes(buf);
+        // The server tells why it can't take the client instead
+        if new_port == SERVER_BUSY_PORT {
+            return match MessageReceiver::new(&stream) {
+                Ok(busy_message) => Err(ClientError::ServerBusy(busy_message.arguments)),
+                Err(e) => Err(ClientError::IOError(e.to_string())),
+            };
+        }
         let new_addr: &str = &(ip_addr.to_string() + ":" + new_port.to_string().as_str());
         println!("New address to connect to: {}", new_addr);
 
@@ -285,6 +327,9 @@ impl Connection {
             MessageKind::Success => {
                 return Ok(());
             }
+            MessageKind::Error if confirmation_message.file_busy => {
+                return Err(ClientError::FileBusy(confirmation_message.arguments));
+            }
             MessageKind::Error => {
                 println!("{}", &confirmation_message.arguments);
                 return Ok(());
@@ -296,10 +341,32 @@ impl Connection {
     fn down(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
         let help: String = "Help:
     \tdown [server-file] [local-dest]
+    \tdown --tar [server-dir] [local-dest]
     \t[server-file]: 'quicksort.pdf'
-    \t[local-dest]: '/home/user/parfs-receive/'"
+    \t[server-dir]: 'project'
+    \t[local-dest]: '/home/user/parfs-receive/'
+    \tIf [local-dest] exists: --overwrite (default), --no-clobber, --skip, --rename, --newer
+    \t-p, --preserve: keep the modification time and permissions of [server-file]"
             .to_string();
 
+        // "down --tar" downloads a whole directory as a tar archive
+        let (flags, tokens) = split_flags(tokens);
+        let mut as_tar: bool = false;
+        let mut preserve: bool = false;
+        let mut policy: OverwritePolicy = OverwritePolicy::default();
+        for flag in flags {
+            if flag == "--tar" {
+                as_tar = true;
+            } else if flag == "-p" || flag == "--preserve" {
+                preserve = true;
+            } else {
+                match OverwritePolicy::from_flag(flag) {
+                    Some(flag_policy) => policy = flag_policy,
+                    None => return Err(ClientError::InvalidFlag(flag.to_string(), help)),
+                }
+            }
+        }
+
         // currently only supports non-spaced file paths
         // TODO: support quotation file paths
         if tokens.len() != 3 {
@@ -314,15 +381,44 @@ impl Connection {
         //Check that download location is valid
         let mut download_location = PathBuf::from(tokens[2]);
         if download_location.is_dir() {
-            download_location = download_location.join(tokens[1]);
+            download_location = match as_tar {
+                true => {
+                    let dir_name = PathBuf::from(tokens[1].trim_end_matches('/'));
+                    let dir_name = match dir_name.file_name() {
+                        Some(name) => name.to_str().unwrap().to_string(),
+                        None => "archive".to_string(),
+                    };
+                    download_location.join(dir_name + ".tar")
+                }
+                false => download_location.join(tokens[1]),
+            };
         }
         if (!download_location.exists() && !download_location.parent().unwrap().is_dir()) || download_location.is_dir(){
             return Err(ClientError::DestinationError(tokens[2].to_string()));
         }
 
+        // Apply the overwrite policy. OverwritePolicy::Newer needs the modification time of the server's copy,
+        // so it is only decided once the file message arrives.
+        if policy != OverwritePolicy::Newer {
+            match policy.resolve(&download_location, None) {
+                Resolution::Write(write_path) => download_location = write_path,
+                Resolution::Skip => {
+                    println!("Skipped {}: file already exists", download_location.to_str().unwrap());
+                    return Ok(());
+                }
+                Resolution::Fail => {
+                    return Err(ClientError::FileExists(download_location.to_str().unwrap().to_string()))
+                }
+            }
+        }
+
         // Sends down request
+        let message_kind: MessageKind = match as_tar {
+            true => MessageKind::DownTar,
+            false => MessageKind::Down,
+        };
         let message_sender: MessageSender =
-            MessageSender::new(MessageKind::Down, tokens[1].to_string(), None);
+            MessageSender::new(message_kind, tokens[1].to_string(), None);
         let ms_result: Result<(), Error> = message_sender.send_message(&tcp_stream);
         match ms_result {
             Err(e) => {
@@ -341,6 +437,9 @@ impl Connection {
 
         // Double check to see message is of MessageKind::File
         match payload_message.command {
+            MessageKind::Error if payload_message.file_busy => {
+                return Err(ClientError::FileBusy(payload_message.arguments))
+            }
             MessageKind::Error => {
                 return Err(ClientError::DownloadError(payload_message.arguments))
             }
@@ -348,8 +447,25 @@ impl Connection {
             _ => return Err(ClientError::MessageError),
         };
 
+        if policy == OverwritePolicy::Newer
+            && download_location.exists()
+            && !is_newer(payload_message.modified(), &download_location)
+        {
+            if let Err(e) = payload_message.discard(tcp_stream) {
+                return Err(ClientError::IOError(e.to_string()));
+            }
+            println!("Skipped {}: local copy is not older", download_location.to_str().unwrap());
+            return Ok(());
+        }
+
         // Start writing to local destination
-        match payload_message.write_to(tcp_stream, PathBuf::from(download_location)) {
+        match payload_message.write_to(tcp_stream, PathBuf::from(download_location), preserve) {
+            // The server couldn't send all of it, and says why next
+            Err(e) if PayloadAborted::of(&e) => match MessageReceiver::new(tcp_stream) {
+                Ok(reply) if reply.file_busy => return Err(ClientError::FileBusy(reply.arguments)),
+                Ok(reply) => return Err(ClientError::DownloadError(reply.arguments)),
+                Err(reply_error) => return Err(ClientError::IOError(reply_error.to_string())),
+            },
             Err(e) => return Err(ClientError::WriteError(e.to_string())),
             Ok(()) => Ok(()),
         }
@@ -358,10 +474,38 @@ impl Connection {
     fn up(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
         let help: String = "Help:
     \tup [local-file] [server-file]
+    \tup --extract [local-archive] [server-dir]
     \t[local-file]: 'quicksort.pdf'
-    \t[server-file]: 'quicksort.pdf'"
+    \t[server-file]: 'quicksort.pdf'
+    \t[local-archive]: 'project.tar'
+    \t[server-dir]: 'projects'
+    \tIf [server-file] exists: --overwrite (default), --no-clobber, --skip, --rename, --newer
+    \tWith --extract these apply to each file in [local-archive]
+    \t--delta: only send the parts of [local-file] that differ from [server-file]
+    \t-p, --preserve: keep the modification time and permissions of [local-file]"
             .to_string();
 
+        // "up --extract" uploads a tar archive which the server unpacks into a directory
+        let (flags, tokens) = split_flags(tokens);
+        let mut extract: bool = false;
+        let mut delta: bool = false;
+        let mut preserve: bool = false;
+        let mut policy: OverwritePolicy = OverwritePolicy::default();
+        for flag in flags {
+            if flag == "--extract" {
+                extract = true;
+            } else if flag == "--delta" {
+                delta = true;
+            } else if flag == "-p" || flag == "--preserve" {
+                preserve = true;
+            } else {
+                match OverwritePolicy::from_flag(flag) {
+                    Some(flag_policy) => policy = flag_policy,
+                    None => return Err(ClientError::InvalidFlag(flag.to_string(), help)),
+                }
+            }
+        }
+
         let tcp_stream: &TcpStream = match &self.stream {
             Some(tcp) => &tcp,
             None => {
@@ -371,6 +515,13 @@ impl Connection {
         if tokens.len() != 3 {
             return Err(ClientError::WrongArgumentNum(help));
         }
+        if extract && delta {
+            return Err(ClientError::InvalidFlag("--delta".to_string(), help));
+        }
+        // The attributes travel with a single file, the server doesn't set any on the files of an archive
+        if extract && preserve {
+            return Err(ClientError::InvalidFlag("--preserve".to_string(), help));
+        }
 
         let file_path: PathBuf = PathBuf::from(tokens[1]);
         if !file_path.is_file() {
@@ -378,9 +529,29 @@ impl Connection {
                 file_path.to_str().unwrap().to_string(),
             ));
         }
-        // Sends down request
+        // Sends up request
+        let message_kind: MessageKind = match extract {
+            true => MessageKind::UpExtract,
+            false => MessageKind::Up,
+        };
+        // The server applies the overwrite policy, so it needs to know how old the local file is. The entries of an
+        // archive carry their own modification times.
+        let mut arguments: String = tokens[2].to_string();
+        add_option(&mut arguments, "policy", policy.as_str());
+        if !extract {
+            if let Ok(modified) = file_path.metadata().and_then(|metadata| metadata.modified()) {
+                add_option(&mut arguments, "mtime", &encode_time(modified));
+            }
+            if delta {
+                add_option(&mut arguments, "delta", "1");
+            }
+        }
+        // Lets the server refuse a file that won't fit before it is sent
+        if let Ok(metadata) = file_path.metadata() {
+            add_option(&mut arguments, "size", &metadata.len().to_string());
+        }
         let message_sender: MessageSender =
-            MessageSender::new(MessageKind::Up, tokens[2].to_string(), None);
+            MessageSender::new(message_kind, arguments, None);
         let ms_result: Result<(), Error> = message_sender.send_message(&tcp_stream);
         match ms_result {
             Err(e) => {
@@ -388,6 +559,14 @@ impl Connection {
             }
             _ => {}
         }
+        // The attributes travel with the file, so the server sets them once it has written it
+        let mut file_arguments: String = "".to_string();
+        if preserve {
+            if let Ok(metadata) = file_path.metadata() {
+                FileAttributes::of(&metadata).add_to(&mut file_arguments);
+            }
+        }
+
         // Receives incoming server message
         let server_message: MessageReceiver = match MessageReceiver::new(tcp_stream) {
             Ok(server_message) => server_message,
@@ -396,19 +575,59 @@ impl Connection {
             }
         };
         // Double check to see message is of MessageKind::Success
+        // A success carrying a message means the server won't take the file, e.g. it was skipped
+        // For a delta upload, the server replies with the signatures of its copy if it has one
         match server_message.command {
+            MessageKind::Error if server_message.file_busy => return Err(ClientError::FileBusy(server_message.arguments)),
             MessageKind::Error => return Err(ClientError::UploadError(server_message.arguments)),
-            MessageKind::Success => (),
+            MessageKind::Success => {
+                if !server_message.arguments.is_empty() {
+                    println!("{}", &server_message.arguments);
+                    return Ok(());
+                }
+
+                //  Sending the file
+                let file_message = MessageSender::new(MessageKind::File, file_arguments, Some(file_path));
+                match file_message.send_message(&tcp_stream) {
+                    Ok(_) => {}
+                    Err(e) => return Err(ClientError::IOError(e.to_string())),
+                }
+            }
+            MessageKind::Signature => {
+                let signatures = match server_message.read_payload(tcp_stream) {
+                    Ok(payload) => payload,
+                    Err(e) => return Err(ClientError::IOError(e.to_string())),
+                };
+                let (block_size, signatures) = match decode_signatures(&signatures) {
+                    Some(decoded) => decoded,
+                    None => return Err(ClientError::MessageError),
+                };
+
+                //  Sending the delta
+                let delta_message = MessageSender::new(MessageKind::Delta, file_arguments, None);
+                let delta_result = delta_message.send_chunked(&tcp_stream, |writer| {
+                    let source = io::BufReader::with_capacity(BUFFER_SIZE, fs::File::open(&file_path)?);
+                    return write_delta(source, block_size, &signatures, writer);
+                });
+                match delta_result {
+                    Ok(Ok(stats)) => println!(
+                        "Sent {} bytes of changes, reused {} blocks of {} bytes",
+                        stats.literal_bytes, stats.copied_blocks, block_size
+                    ),
+                    // The delta was aborted, the server replies with an error which is read so the next request gets
+                    // its own reply
+                    Ok(Err(e)) => {
+                        if let Err(reply_error) = MessageReceiver::new(tcp_stream) {
+                            return Err(ClientError::IOError(reply_error.to_string()));
+                        }
+                        return Err(ClientError::UploadError(e.to_string()));
+                    }
+                    Err(e) => return Err(ClientError::IOError(e.to_string())),
+                }
+            }
             _ => return Err(ClientError::MessageError),
         };
 
-        //  Sending the file
-        let file_message = MessageSender::new(MessageKind::File, "".to_string(), Some(file_path));
-        match file_message.send_message(&tcp_stream) {
-            Ok(_) => {}
-            Err(e) => return Err(ClientError::IOError(e.to_string())),
-        }
-
         // Check confirmation message
         let confirmation_message: MessageReceiver = match MessageReceiver::new(&tcp_stream) {
             Ok(server_message) => server_message,
@@ -419,8 +638,14 @@ impl Connection {
 
         match confirmation_message.command {
             MessageKind::Success => {
+                if !confirmation_message.arguments.is_empty() {
+                    println!("{}", &confirmation_message.arguments);
+                }
                 return Ok(());
             }
+            MessageKind::Error if confirmation_message.file_busy => {
+                return Err(ClientError::FileBusy(confirmation_message.arguments));
+            }
             MessageKind::Error => {
                 println!("{}", &confirmation_message.arguments);
                 return Ok(());
@@ -429,6 +654,433 @@ impl Connection {
         }
     }
 
+    fn versions(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tversions [server-file]".to_string();
+        if tokens.len() != 2 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        // Lists the versions as "version  modified  size"
+        let reply: MessageReceiver = self.request(MessageKind::Versions, tokens[1].to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn restore(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:
+    \trestore [server-file] [version]
+    \t[version]: a version number listed by 'versions [server-file]'"
+            .to_string();
+        if tokens.len() != 3 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let mut arguments: String = tokens[1].to_string();
+        add_option(&mut arguments, "version", tokens[2]);
+        let reply: MessageReceiver = self.request(MessageKind::Restore, arguments)?;
+        match reply.command {
+            MessageKind::Success => {
+                println!("Restored version {} of {}", tokens[2], tokens[1]);
+                return Ok(());
+            }
+            MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn rm(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\trm [server-path]".to_string();
+        if tokens.len() != 2 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let reply: MessageReceiver = self.request(MessageKind::Rm, tokens[1].to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn login(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tlogin [user] [password]".to_string();
+        if tokens.len() != 3 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let mut arguments: String = tokens[1].to_string();
+        add_option(&mut arguments, "password", tokens[2]);
+        let reply: MessageReceiver = self.request(MessageKind::Login, arguments)?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn quota(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tquota".to_string();
+        if tokens.len() != 1 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let reply: MessageReceiver = self.request(MessageKind::Quota, "".to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn stat(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tstat [server-path]".to_string();
+        if tokens.len() != 2 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let reply: MessageReceiver = self.request(MessageKind::Stat, tokens[1].to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn df(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tdf".to_string();
+        if tokens.len() != 1 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let reply: MessageReceiver = self.request(MessageKind::Df, "".to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn locks(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tlocks".to_string();
+        if tokens.len() != 1 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let reply: MessageReceiver = self.request(MessageKind::Locks, "".to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn sessions(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tsessions".to_string();
+        if tokens.len() != 1 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let reply: MessageReceiver = self.request(MessageKind::Sessions, "".to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn lock(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:
+    \tlock [server-file] [minutes]
+    \t[minutes]: how long the lock lasts, 30 if not given"
+            .to_string();
+        if tokens.len() != 2 && tokens.len() != 3 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let mut arguments: String = tokens[1].to_string();
+        if let Some(minutes) = tokens.get(2) {
+            add_option(&mut arguments, "minutes", minutes);
+        }
+        let reply: MessageReceiver = self.request(MessageKind::Lock, arguments)?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn unlock(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:\n\tunlock [server-file]".to_string();
+        if tokens.len() != 2 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+
+        let reply: MessageReceiver = self.request(MessageKind::Unlock, tokens[1].to_string())?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn trash(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:
+    \ttrash ls
+    \ttrash restore [id]
+    \ttrash empty
+    \t[id]: an id listed by 'trash ls'"
+            .to_string();
+
+        let arguments: String = match tokens[1..] {
+            ["ls"] => "ls".to_string(),
+            ["empty"] => "empty".to_string(),
+            ["restore", id] => {
+                let mut arguments = "restore".to_string();
+                add_option(&mut arguments, "id", id);
+                arguments
+            }
+            _ => return Err(ClientError::WrongArgumentNum(help)),
+        };
+
+        // Lists the trash as "id  deleted  original path"
+        let reply: MessageReceiver = self.request(MessageKind::Trash, arguments)?;
+        match reply.command {
+            MessageKind::Success | MessageKind::Error => {
+                if !reply.arguments.is_empty() {
+                    println!("{}", &reply.arguments);
+                }
+                return Ok(());
+            }
+            _ => Err(ClientError::MessageError),
+        }
+    }
+
+    fn sync(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:
+    \tsync [local-dir] [server-dir] [--push|--pull|--both] [--delete] [--dry-run]
+    \t--push: make the server folder match the local one
+    \t--pull: make the local folder match the server one
+    \t--both: copy changes both ways, the newer file wins (default)
+    \t--delete: delete what doesn't exist on the other side (not with --both)
+    \t--dry-run: only print what would be done"
+            .to_string();
+
+        let (flags, tokens) = split_flags(tokens);
+        let mut mode: SyncMode = SyncMode::Both;
+        let mut delete: bool = false;
+        let mut dry_run: bool = false;
+        for flag in flags {
+            match flag {
+                "--push" => mode = SyncMode::Push,
+                "--pull" => mode = SyncMode::Pull,
+                "--both" => mode = SyncMode::Both,
+                "--delete" => delete = true,
+                "--dry-run" => dry_run = true,
+                _ => return Err(ClientError::InvalidFlag(flag.to_string(), help)),
+            }
+        }
+        if tokens.len() != 3 {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+        if delete && mode == SyncMode::Both {
+            return Err(ClientError::SyncError("--delete needs --push or --pull".to_string()));
+        }
+        let local_dir: PathBuf = PathBuf::from(tokens[1]);
+        if !local_dir.is_dir() {
+            return Err(ClientError::DestinationError(tokens[1].to_string()));
+        }
+        let remote_dir: &str = tokens[2].trim_end_matches('/');
+
+        // Compare the server's tree with the local one
+        let reply: MessageReceiver = self.request(MessageKind::Manifest, remote_dir.to_string())?;
+        let remote_manifest: Manifest = match reply.command {
+            MessageKind::Success => match decode_manifest(&reply.arguments) {
+                Some(manifest) => manifest,
+                None => return Err(ClientError::MessageError),
+            },
+            MessageKind::Error => return Err(ClientError::SyncError(reply.arguments)),
+            _ => return Err(ClientError::MessageError),
+        };
+        let local_manifest: Manifest = match local_manifest(&local_dir) {
+            Ok(manifest) => manifest,
+            Err(e) => return Err(ClientError::IOError(e.to_string())),
+        };
+
+        let actions: Vec<SyncAction> = plan_sync(&local_manifest, &remote_manifest, mode, delete);
+        if actions.is_empty() {
+            println!("Already in sync");
+            return Ok(());
+        }
+        for action in actions {
+            if dry_run {
+                println!("Would {}", action);
+                continue;
+            }
+            println!("{}", action);
+            self.run_sync_action(&action, &local_dir, remote_dir)?;
+        }
+        return Ok(());
+    }
+
+    fn run_sync_action(&self, action: &SyncAction, local_dir: &Path, remote_dir: &str) -> Result<(), ClientError> {
+        let local_path = |path: &String| local_dir.join(path);
+        let remote_path = |path: &String| format!("{}/{}", remote_dir, path);
+        // up and down take their paths as text, which a local name that isn't valid UTF-8 can't be passed as
+        let local_text = |path: &String| -> Result<String, ClientError> {
+            let local_path = local_path(path);
+            match local_path.to_str() {
+                Some(text) => return Ok(text.to_string()),
+                None => return Err(ClientError::SyncError(format!("{} is not a valid UTF-8 path", local_path.to_string_lossy()))),
+            }
+        };
+        let io_result = match action {
+            SyncAction::Upload(path) => {
+                let local_path = local_text(path)?;
+                return self.up(&vec!["up", &local_path, &remote_path(path)]);
+            }
+            SyncAction::Download(path) => {
+                let local_path = local_text(path)?;
+                return self.down(&vec!["down", &remote_path(path), &local_path]);
+            }
+            SyncAction::MkdirRemote(path) | SyncAction::DeleteRemote(path) => {
+                let message_kind: MessageKind = match action {
+                    SyncAction::MkdirRemote(_) => MessageKind::Mkdir,
+                    _ => MessageKind::Rm,
+                };
+                let reply: MessageReceiver = self.request(message_kind, remote_path(path))?;
+                if let MessageKind::Error = reply.command {
+                    println!("{}", &reply.arguments);
+                }
+                return Ok(());
+            }
+            SyncAction::MkdirLocal(path) => fs::create_dir_all(local_path(path)),
+            SyncAction::DeleteLocal(path) => {
+                let local_path = local_path(path);
+                if local_path.is_dir() {
+                    fs::remove_dir_all(local_path)
+                } else {
+                    fs::remove_file(local_path)
+                }
+            }
+            SyncAction::Conflict(_) => Ok(()),
+        };
+        match io_result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(ClientError::WriteError(e.to_string())),
+        }
+    }
+
+    // Prints or runs a command for the changes the server reports in a directory until Enter is pressed
+    fn watch(&self, tokens: &Vec<&str>) -> Result<(), ClientError> {
+        let help: String = "Help:
+    \twatch [server-dir] [--exec command]
+    \tPrints what is created, modified, deleted or renamed in [server-dir] until Enter is pressed
+    \t--exec: runs command for every change instead, with PARFS_EVENT, PARFS_PATH and PARFS_OLD_PATH set"
+            .to_string();
+
+        // Everything after --exec is the command, which may have flags of its own
+        let (tokens, exec): (&[&str], Option<String>) = match tokens.iter().position(|token| *token == "--exec") {
+            Some(index) => (&tokens[..index], Some(tokens[index + 1..].join(" "))),
+            None => (&tokens[..], None),
+        };
+        if tokens.len() != 2 || exec.as_deref() == Some("") {
+            return Err(ClientError::WrongArgumentNum(help));
+        }
+        let tcp_stream: &TcpStream = match &self.stream {
+            Some(tcp) => &tcp,
+            None => {
+                return Err(ClientError::ConnectionError);
+            }
+        };
+
+        let reply: MessageReceiver = self.request(MessageKind::Watch, tokens[1].to_string())?;
+        match reply.command {
+            MessageKind::Success => println!("Watching {}, press Enter to stop", tokens[1]),
+            MessageKind::Error => {
+                println!("{}", &reply.arguments);
+                return Ok(());
+            }
+            _ => return Err(ClientError::MessageError),
+        }
+
+        // Enter stops watching. The unwatch is sent from another thread while this one keeps receiving events,
+        // the server answers it once it has sent the last event.
+        let unwatch_stream: TcpStream = match tcp_stream.try_clone() {
+            Ok(stream) => stream,
+            Err(e) => return Err(ClientError::IOError(e.to_string())),
+        };
+        thread::spawn(move || {
+            let mut input = String::new();
+            let _ = io::stdin().read_line(&mut input);
+            let unwatch_message = MessageSender::new(MessageKind::Unwatch, "".to_string(), None);
+            if let Err(e) = unwatch_message.send_message(&unwatch_stream) {
+                println!("{}", ClientError::IOError(e.to_string()));
+            }
+        });
+
+        loop {
+            let server_message: MessageReceiver = match MessageReceiver::new(tcp_stream) {
+                Ok(server_message) => server_message,
+                Err(e) => return Err(ClientError::IOError(e.to_string())),
+            };
+            match server_message.command {
+                MessageKind::Event => run_watch_event(&server_message.arguments, &exec),
+                MessageKind::Success => return Ok(()),
+                MessageKind::Error => {
+                    println!("{}", &server_message.arguments);
+                    return Ok(());
+                }
+                _ => return Err(ClientError::MessageError),
+            }
+        }
+    }
+
+    // Sends a request without a payload and waits for the reply of the server. A file kept busy by another session
+    // is an error, so callers only see the replies to requests that were handled.
+    fn request(&self, command: MessageKind, arguments: String) -> Result<MessageReceiver, ClientError> {
+        let tcp_stream: &TcpStream = match &self.stream {
+            Some(tcp) => &tcp,
+            None => {
+                return Err(ClientError::ConnectionError);
+            }
+        };
+        let message_sender: MessageSender = MessageSender::new(command, arguments, None);
+        if let Err(e) = message_sender.send_message(tcp_stream) {
+            return Err(ClientError::IOError(e.to_string()));
+        }
+        match MessageReceiver::new(tcp_stream) {
+            Ok(server_message) if server_message.file_busy => Err(ClientError::FileBusy(server_message.arguments)),
+            Ok(server_message) => Ok(server_message),
+            Err(e) => Err(ClientError::IOError(e.to_string())),
+        }
+    }
+
     fn help(&self) {
         for command in Command::iterator() {
             let command_str: String = command.get_str();
@@ -450,3 +1102,46 @@ impl Connection {
         println!("Current working directory is '{}'", self.cwd);
     }
 }
+
+// Describes the local tree below dir in the same way as the server does
+fn local_manifest(dir: &Path) -> io::Result<Manifest> {
+    let mut manifest = Manifest::new();
+    for (relative_path, is_dir) in list_tree(dir, &|_| false)? {
+        let entry = match is_dir {
+            true => ManifestEntry::Directory,
+            false => file_entry(&dir.join(&relative_path))?,
+        };
+        manifest.insert(manifest_path(&relative_path), entry);
+    }
+    return Ok(manifest);
+}
+
+// Prints an event of a watched directory, "event\tpath" or "renamed\told path\tnew path", or runs exec for it
+fn run_watch_event(event: &str, exec: &Option<String>) {
+    let fields: Vec<&str> = event.split('\t').collect();
+    let (old_path, path) = match fields[..] {
+        [_, old_path, path] => (old_path, path),
+        [_, path] => ("", path),
+        _ => return,
+    };
+    let exec = match exec {
+        Some(exec) => exec,
+        None => {
+            match old_path {
+                "" => println!("{}\t{}", fields[0], path),
+                _ => println!("{}\t{} -> {}", fields[0], old_path, path),
+            }
+            return;
+        }
+    };
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(exec)
+        .env("PARFS_EVENT", fields[0])
+        .env("PARFS_PATH", path)
+        .env("PARFS_OLD_PATH", old_path)
+        .status();
+    if let Err(e) = status {
+        println!("Error: Cannot run {}: {}", exec, e);
+    }
+}
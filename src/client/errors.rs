@@ -13,6 +13,11 @@ pub enum ClientError {
     UploadError(String),
     FileError(String),
     DestinationError(String),
+    InvalidFlag(String, String),
+    FileExists(String),
+    SyncError(String),
+    ServerBusy(String),
+    FileBusy(String),
 }
 
 impl fmt::Display for ClientError {
@@ -28,7 +33,12 @@ impl fmt::Display for ClientError {
             Self::WriteError(error) => f.write_str(&format!("Error: There was an issue the file to the local machine. \n {}", error)),
             Self::DestinationError(error) => f.write_str(&format!("Invalid path: {}", error)),
             Self::UploadError(error) => f.write_str(&format!("Error: {}", error)),
-            Self::FileError(file) => f.write_str(&format!("Error: Cannot access {}: no such file", file))
+            Self::FileError(file) => f.write_str(&format!("Error: Cannot access {}: no such file", file)),
+            Self::InvalidFlag(flag, help) => f.write_str(&format!("Error: Unknown option {}. \n {}", flag, help)),
+            Self::FileExists(file) => f.write_str(&format!("Error: File exists at {}: not overwritten", file)),
+            Self::SyncError(error) => f.write_str(&format!("Error: {}", error)),
+            Self::ServerBusy(message) => f.write_str(&format!("Error: {}", message)),
+            Self::FileBusy(message) => f.write_str(&format!("Error: {}. \n Wait for the other session to finish, then try again.", message))
         }
     }
 }
@@ -1,14 +1,16 @@
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, BufWriter};
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::path::PathBuf;
 use std::str::from_utf8;
+use std::time::SystemTime;
 
-use crate::client::utilities::print_progress;
+use crate::client::utilities::{partial_path_for, print_progress, print_received};
+use crate::utilities::decode_time;
 
-use crate::message::{MessageKind, BUFFER_SIZE, HEADER_SIZE};
+use crate::message::{split_options, ChunkedReader, FileAttributes, MessageKind, SizedReader, BUFFER_SIZE, CHUNKED_PAYLOAD, FILE_BUSY_OPTION, HEADER_SIZE};
 
 
 #[derive(Debug)]
@@ -16,6 +18,8 @@ pub struct MessageReceiver {
     pub command: MessageKind,
     pub arguments: String,
     pub payload_size: u64,
+    // Whether this is an Error saying that a file was kept busy by another session
+    pub file_busy: bool,
 }
 
 impl MessageReceiver {
@@ -33,8 +37,17 @@ impl MessageReceiver {
         // Read in arguments
         let mut argument_bytes: Vec<u8> = vec![0u8; argument_size as usize];
         tcpstream.read_exact(&mut argument_bytes)?;
-        let argument_string = from_utf8(&argument_bytes).unwrap().to_string();
-        payload_size -= HEADER_SIZE as u64 + argument_size as u64;
+        let mut argument_string = from_utf8(&argument_bytes).unwrap().to_string();
+        let mut file_busy: bool = false;
+        if command == MessageKind::Error {
+            let (message, options) = split_options(&argument_string);
+            file_busy = options.contains_key(FILE_BUSY_OPTION);
+            argument_string = message;
+        }
+        // Chunked payloads keep the marker as their size as the real size is unknown
+        if payload_size != CHUNKED_PAYLOAD {
+            payload_size -= HEADER_SIZE as u64 + argument_size as u64;
+        }
 
         // println!("message size: {}", payload_size);
         // println!("command: {}", headers[8]);
@@ -47,19 +60,46 @@ impl MessageReceiver {
             command: command,
             arguments: argument_string,
             payload_size: payload_size,
+            file_busy: file_busy,
         };
 
         Ok(message_receiver)
     }
 
-    // Writes to a file_path
+    // Writes to a file_path. With preserve, the file gets the modification time and permissions sent with it.
+    // file_path is only replaced once the whole payload arrived, so a transfer that fails or is aborted by the
+    // server leaves what was there before.
     pub fn write_to(
         self,
         tcpstream: &TcpStream,
         file_path: PathBuf,
+        preserve: bool,
+    ) -> io::Result<()> {
+        let (_, options) = split_options(&self.arguments);
+        let attributes = FileAttributes::from_options(&options);
+        let partial_path = partial_path_for(&file_path);
+        let result = self.write_payload(tcpstream, &partial_path).and_then(|()| {
+            if preserve {
+                attributes.apply(&File::options().write(true).open(&partial_path)?)?;
+            }
+            return fs::rename(&partial_path, &file_path);
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&partial_path);
+        }
+        return result;
+    }
+
+    fn write_payload(
+        &self,
+        tcpstream: &TcpStream,
+        file_path: &PathBuf,
     ) -> io::Result<()> {
         let file = File::create(file_path)?;
         let mut writer = BufWriter::new(file);
+        if self.payload_size == CHUNKED_PAYLOAD {
+            return write_chunked(tcpstream, writer);
+        }
         let mut byte_count: u64 = 0;
         let mut reader = BufReader::with_capacity(BUFFER_SIZE, tcpstream );
         let capacity = reader.capacity() as u64;
@@ -78,7 +118,11 @@ impl MessageReceiver {
                 return Ok(());
             } else {
                 let buffer = reader.fill_buf()?;
-                writer.write(buffer)?;
+                // The connection closed before all of the payload arrived
+                if buffer.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed during the transfer"));
+                }
+                writer.write_all(buffer)?;
                 let length = buffer.len();
                 reader.consume(length);
                 byte_count += length as u64;
@@ -91,8 +135,51 @@ impl MessageReceiver {
         return Ok(());
     }
 
+    // Modification time of the file carried by a File message, if the sender included it
+    pub fn modified(&self) -> Option<SystemTime> {
+        let (_, options) = split_options(&self.arguments);
+        return options.get("mtime").and_then(|mtime| decode_time(mtime));
+    }
+
+    // Reads the whole payload into memory, for small payloads that aren't files
+    pub fn read_payload(self, tcpstream: &TcpStream) -> io::Result<Vec<u8>> {
+        let mut payload: Vec<u8> = vec![];
+        match self.payload_size {
+            CHUNKED_PAYLOAD => ChunkedReader::new(tcpstream).read_to_end(&mut payload)?,
+            _ => SizedReader::new(tcpstream, self.payload_size).read_to_end(&mut payload)?,
+        };
+        return Ok(payload);
+    }
+
+    // Reads and throws away the payload, e.g. when a downloaded file isn't wanted after all
+    pub fn discard(self, tcpstream: &TcpStream) -> io::Result<()> {
+        match self.payload_size {
+            CHUNKED_PAYLOAD => io::copy(&mut ChunkedReader::new(tcpstream), &mut io::sink())?,
+            _ => io::copy(&mut SizedReader::new(tcpstream, self.payload_size), &mut io::sink())?,
+        };
+        return Ok(());
+    }
+
     /* pub fn get_reader(self) -> BufReader<&'a TcpStream> {
         return self.payload;
     } */
 }
 
+// Writes a payload of unknown size, which arrives in chunks, to writer
+fn write_chunked(tcpstream: &TcpStream, mut writer: BufWriter<File>) -> io::Result<()> {
+    let mut reader = ChunkedReader::new(tcpstream);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut byte_count: u64 = 0;
+    loop {
+        print_received(byte_count);
+        let length = reader.read(&mut buffer)?;
+        if length == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..length])?;
+        byte_count += length as u64;
+    }
+    print!("\n");
+    writer.flush()?;
+    return Ok(());
+}
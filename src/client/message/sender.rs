@@ -5,7 +5,7 @@ use std::net::TcpStream;
 use std::path::PathBuf;
 
 use crate::client::utilities::print_progress;
-use crate::message::{MessageKind, BUFFER_SIZE, HEADER_SIZE};
+use crate::message::{ChunkedWriter, MessageKind, BUFFER_SIZE, CHUNKED_PAYLOAD, HEADER_SIZE};
 
 #[derive(Debug)]
 pub struct MessageSender {
@@ -58,6 +58,28 @@ impl MessageSender {
         return Ok(());
     }
 
+    // Sends a payload of unknown size in chunks, as it is written by produce. If produce fails, the payload is aborted
+    // and its error is returned inside Ok, as the server still replies to the message. Err is an error of the connection.
+    pub fn send_chunked<F, T>(mut self, mut writer: &TcpStream, produce: F) -> io::Result<io::Result<T>>
+    where
+        F: FnOnce(&mut ChunkedWriter<&TcpStream>) -> io::Result<T>,
+    {
+        let (mut headers, _) = self.generate_message()?;
+        headers[0..8].copy_from_slice(&CHUNKED_PAYLOAD.to_be_bytes());
+        writer.write_all(&headers)?;
+        let mut chunked_writer = ChunkedWriter::new(writer);
+        let produced = match produce(&mut chunked_writer) {
+            Ok(produced) => produced,
+            Err(e) => {
+                chunked_writer.abort()?;
+                return Ok(Err(e));
+            }
+        };
+        writer = chunked_writer.finish()?;
+        writer.flush()?;
+        return Ok(Ok(produced));
+    }
+
     // idk how to chain the vector and bufreader into a single iterator bro
     fn generate_message(&mut self) -> io::Result<(Vec<u8>, Option<BufReader<File>>)> {
         let mut payload_length: u64 = 0;
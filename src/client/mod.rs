@@ -1,4 +1,5 @@
 pub mod connection;
 pub mod utilities;
 pub mod errors;
-pub mod message;
\ No newline at end of file
+pub mod message;
+pub mod sync;
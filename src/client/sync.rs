@@ -0,0 +1,238 @@
+use std::fmt;
+
+use crate::manifest::{Manifest, ManifestEntry};
+
+// Direction of a sync
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncMode {
+    // Make the server match the local directory
+    Push,
+    // Make the local directory match the server
+    Pull,
+    // Copy new files both ways, the newer copy wins when both changed
+    Both,
+}
+
+// One step of a sync. Paths are relative to the directories being synced.
+#[derive(Debug, PartialEq)]
+pub enum SyncAction {
+    MkdirRemote(String),
+    MkdirLocal(String),
+    Upload(String),
+    Download(String),
+    DeleteRemote(String),
+    DeleteLocal(String),
+    // Both sides changed and neither is newer, or a file on one side is a directory on the other
+    Conflict(String),
+}
+
+impl fmt::Display for SyncAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MkdirRemote(path) => f.write_str(&format!("mkdir (server)   {}", path)),
+            Self::MkdirLocal(path) => f.write_str(&format!("mkdir (local)    {}", path)),
+            Self::Upload(path) => f.write_str(&format!("up               {}", path)),
+            Self::Download(path) => f.write_str(&format!("down             {}", path)),
+            Self::DeleteRemote(path) => f.write_str(&format!("delete (server)  {}", path)),
+            Self::DeleteLocal(path) => f.write_str(&format!("delete (local)   {}", path)),
+            Self::Conflict(path) => f.write_str(&format!("conflict, kept   {}", path)),
+        }
+    }
+}
+
+// Works out what has to be done to sync two trees. Directories are created before the files in them,
+// and deleting a directory is not followed by deleting what's inside it. Nothing is done inside a path that is a file
+// on one side and a directory on the other, as the conflict keeps both as they are.
+pub fn plan_sync(local: &Manifest, remote: &Manifest, mode: SyncMode, delete: bool) -> Vec<SyncAction> {
+    let push = mode != SyncMode::Pull;
+    let pull = mode != SyncMode::Push;
+    let mut actions: Vec<SyncAction> = vec![];
+    let mut deletes: Vec<SyncAction> = vec![];
+    // Directories whose content is left alone, because they are deleted or conflict with a file
+    let mut skipped_dirs: Vec<String> = vec![];
+
+    // Manifests are sorted, so a directory always comes before its content
+    let mut paths: Vec<&String> = local.keys().chain(remote.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    for path in paths {
+        if skipped_dirs.iter().any(|dir| path.starts_with(&format!("{}/", dir))) {
+            continue;
+        }
+        match (local.get(path), remote.get(path)) {
+            (Some(ManifestEntry::Directory), Some(ManifestEntry::Directory)) => {}
+            (Some(ManifestEntry::Directory), None) => {
+                if push {
+                    actions.push(SyncAction::MkdirRemote(path.clone()));
+                } else if delete {
+                    deletes.push(SyncAction::DeleteLocal(path.clone()));
+                    skipped_dirs.push(path.clone());
+                }
+            }
+            (None, Some(ManifestEntry::Directory)) => {
+                if pull {
+                    actions.push(SyncAction::MkdirLocal(path.clone()));
+                } else if delete {
+                    deletes.push(SyncAction::DeleteRemote(path.clone()));
+                    skipped_dirs.push(path.clone());
+                }
+            }
+            (Some(ManifestEntry::File { .. }), None) => {
+                if push {
+                    actions.push(SyncAction::Upload(path.clone()));
+                } else if delete {
+                    deletes.push(SyncAction::DeleteLocal(path.clone()));
+                }
+            }
+            (None, Some(ManifestEntry::File { .. })) => {
+                if pull {
+                    actions.push(SyncAction::Download(path.clone()));
+                } else if delete {
+                    deletes.push(SyncAction::DeleteRemote(path.clone()));
+                }
+            }
+            (
+                Some(ManifestEntry::File {
+                    modified: local_modified,
+                    hash: local_hash,
+                    ..
+                }),
+                Some(ManifestEntry::File {
+                    modified: remote_modified,
+                    hash: remote_hash,
+                    ..
+                }),
+            ) => {
+                if local_hash == remote_hash {
+                    continue;
+                }
+                let action = match mode {
+                    SyncMode::Push => SyncAction::Upload(path.clone()),
+                    SyncMode::Pull => SyncAction::Download(path.clone()),
+                    SyncMode::Both => {
+                        if local_modified > remote_modified {
+                            SyncAction::Upload(path.clone())
+                        } else if remote_modified > local_modified {
+                            SyncAction::Download(path.clone())
+                        } else {
+                            SyncAction::Conflict(path.clone())
+                        }
+                    }
+                };
+                actions.push(action);
+            }
+            // A file on one side is a directory on the other
+            _ => {
+                actions.push(SyncAction::Conflict(path.clone()));
+                skipped_dirs.push(path.clone());
+            }
+        }
+    }
+    actions.extend(deletes);
+    return actions;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use SyncAction::*;
+    use SyncMode::*;
+
+    fn file(hash: &str, seconds: u64) -> ManifestEntry {
+        return ManifestEntry::File {
+            size: 1,
+            modified: UNIX_EPOCH + Duration::from_secs(seconds),
+            hash: hash.to_string(),
+        };
+    }
+
+    fn manifest(entries: Vec<(&str, ManifestEntry)>) -> Manifest {
+        return entries.into_iter().map(|(path, entry)| (path.to_string(), entry)).collect();
+    }
+
+    #[test]
+    fn plans_follow_the_mode() {
+        let local = manifest(vec![
+            ("d", ManifestEntry::Directory),
+            ("d/new", file("a", 1)),
+            ("same", file("s", 1)),
+            ("older", file("o1", 1)),
+            ("newer", file("n2", 2)),
+            ("tied", file("t1", 5)),
+        ]);
+        let remote = manifest(vec![
+            ("r", ManifestEntry::Directory),
+            ("r/new", file("b", 1)),
+            ("same", file("s", 9)),
+            ("older", file("o2", 2)),
+            ("newer", file("n1", 1)),
+            ("tied", file("t2", 5)),
+        ]);
+        let cases: Vec<(SyncMode, bool, Vec<SyncAction>)> = vec![
+            (Push, false, vec![MkdirRemote("d".into()), Upload("d/new".into()), Upload("newer".into()), Upload("older".into()), Upload("tied".into())]),
+            (
+                Push,
+                true,
+                vec![MkdirRemote("d".into()), Upload("d/new".into()), Upload("newer".into()), Upload("older".into()), Upload("tied".into()), DeleteRemote("r".into())],
+            ),
+            (Pull, false, vec![Download("newer".into()), Download("older".into()), MkdirLocal("r".into()), Download("r/new".into()), Download("tied".into())]),
+            (
+                Pull,
+                true,
+                vec![Download("newer".into()), Download("older".into()), MkdirLocal("r".into()), Download("r/new".into()), Download("tied".into()), DeleteLocal("d".into())],
+            ),
+            (
+                Both,
+                false,
+                vec![MkdirRemote("d".into()), Upload("d/new".into()), Upload("newer".into()), Download("older".into()), MkdirLocal("r".into()), Download("r/new".into()), Conflict("tied".into())],
+            ),
+        ];
+        for (mode, delete, expected) in cases {
+            assert_eq!(plan_sync(&local, &remote, mode, delete), expected, "{:?} with delete {}", mode, delete);
+        }
+    }
+
+    #[test]
+    fn plans_delete_directories_without_their_content_and_keep_conflicts() {
+        let cases: Vec<(&str, Manifest, Manifest, SyncMode, bool, Vec<SyncAction>)> = vec![
+            (
+                "deleted directory on the server",
+                manifest(vec![]),
+                manifest(vec![("old", ManifestEntry::Directory), ("old/a", file("a", 1)), ("old/b", ManifestEntry::Directory), ("old/b/c", file("c", 1))]),
+                Push,
+                true,
+                vec![DeleteRemote("old".into())],
+            ),
+            (
+                "deleted local directory",
+                manifest(vec![("old", ManifestEntry::Directory), ("old/a", file("a", 1)), ("older", file("o", 1))]),
+                manifest(vec![]),
+                Pull,
+                true,
+                vec![DeleteLocal("old".into()), DeleteLocal("older".into())],
+            ),
+            (
+                "local file where the server has a directory",
+                manifest(vec![("x", file("x", 1))]),
+                manifest(vec![("x", ManifestEntry::Directory), ("x/y", file("y", 1))]),
+                Both,
+                false,
+                vec![Conflict("x".into())],
+            ),
+            (
+                "local directory where the server has a file",
+                manifest(vec![("x", ManifestEntry::Directory), ("x/y", file("y", 1))]),
+                manifest(vec![("x", file("x", 1))]),
+                Push,
+                true,
+                vec![Conflict("x".into())],
+            ),
+        ];
+        for (name, local, remote, mode, delete, expected) in cases {
+            assert_eq!(plan_sync(&local, &remote, mode, delete), expected, "{}", name);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::path::{Path, PathBuf};
 use std::slice::Iter;
 
 #[derive(Debug)]
@@ -8,9 +9,22 @@ pub enum Command {
     Mkdir,
     Cd,
     Ls,
+    Stat,
     Up,
     Down,
     Status,
+    Versions,
+    Restore,
+    Rm,
+    Trash,
+    Sync,
+    Watch,
+    Lock,
+    Unlock,
+    Quota,
+    Df,
+    Locks,
+    Sessions,
 }
 
 impl Command {
@@ -20,7 +34,7 @@ impl Command {
                 "Establishes a connection to a file server. Usage: connect [socket-addr]"
                     .to_string()
             }
-            Command::Login => "Logs in. Usage: (fill in)".to_string(),
+            Command::Login => "Logs in as a user of the server. Usage: login [user] [password]".to_string(),
             Command::Mkdir => {
                 "Makes a folder in the current working directory. Usage: mkdir [name]".to_string()
             }
@@ -28,14 +42,44 @@ impl Command {
             Command::Ls => {
                 "Lists the files in the current working directory. Usage: ls".to_string()
             }
+            Command::Stat => "Shows details of a file or folder on the server. Usage: stat [server-path]".to_string(),
             Command::Up => {
-                "Uploads a file from the local computer to the server. Usage: up [path-to-file]"
+                "Uploads a file from the local computer to the server. Usage: up [--extract] [--delta] [-p] [path-to-file] [server-path]"
                     .to_string()
             }
             Command::Down => {
-                "Downloads a file from the server to the local computer. Usage: (placeholder)"
+                "Downloads a file from the server to the local computer. Usage: down [--tar] [-p] [server-path] [local-dest]"
                     .to_string()
             }
+            Command::Versions => {
+                "Lists the previous versions of a file kept by the server. Usage: versions [file]".to_string()
+            }
+            Command::Restore => {
+                "Restores a previous version of a file. Usage: restore [file] [version]".to_string()
+            }
+            Command::Rm => "Moves a file or folder to the trash. Usage: rm [path]".to_string(),
+            Command::Trash => {
+                "Manages deleted files. Usage: trash ls | trash restore [id] | trash empty".to_string()
+            }
+            Command::Sync => {
+                "Syncs a local folder with a folder on the server. Usage: sync [local-dir] [server-dir] [--push|--pull|--both] [--delete] [--dry-run]"
+                    .to_string()
+            }
+            Command::Watch => {
+                "Reports changes to a folder on the server as they happen. Usage: watch [server-dir] [--exec command]"
+                    .to_string()
+            }
+            Command::Lock => {
+                "Keeps others from changing a file on the server for a while. Usage: lock [server-file] [minutes]"
+                    .to_string()
+            }
+            Command::Unlock => "Releases a lock taken with lock. Usage: unlock [server-file]".to_string(),
+            Command::Quota => "Shows how much you store on the server and how much you may. Usage: quota".to_string(),
+            Command::Df => "Shows the free and used space of the server. Usage: df".to_string(),
+            Command::Locks => "Shows who is using which files on the server, for administrators. Usage: locks".to_string(),
+            Command::Sessions => {
+                "Shows the connected clients and what they are transferring, for administrators. Usage: sessions".to_string()
+            }
             _ => "An error has occurred. Please contact your local system adminstrator.".to_string(),
         }
     }
@@ -47,21 +91,47 @@ impl Command {
             Command::Mkdir => "mkdir".to_string(),
             Command::Cd => "cd".to_string(),
             Command::Ls => "ls".to_string(),
+            Command::Stat => "stat".to_string(),
             Command::Up => "up".to_string(),
             Command::Down => "down".to_string(),
+            Command::Versions => "versions".to_string(),
+            Command::Restore => "restore".to_string(),
+            Command::Rm => "rm".to_string(),
+            Command::Trash => "trash".to_string(),
+            Command::Sync => "sync".to_string(),
+            Command::Watch => "watch".to_string(),
+            Command::Lock => "lock".to_string(),
+            Command::Unlock => "unlock".to_string(),
+            Command::Quota => "quota".to_string(),
+            Command::Df => "df".to_string(),
+            Command::Locks => "locks".to_string(),
+            Command::Sessions => "sessions".to_string(),
             _ => "An error has occurred. Please contact your local system adminstrator.".to_string(),
         }
     }
 
     pub fn iterator() -> Iter<'static, Command> {
-        static COMMANDS: [Command; 7] = [
+        static COMMANDS: [Command; 20] = [
             Command::Connect,
             Command::Login,
             Command::Mkdir,
             Command::Cd,
             Command::Ls,
+            Command::Stat,
             Command::Up,
             Command::Down,
+            Command::Versions,
+            Command::Restore,
+            Command::Rm,
+            Command::Trash,
+            Command::Sync,
+            Command::Watch,
+            Command::Lock,
+            Command::Unlock,
+            Command::Quota,
+            Command::Df,
+            Command::Locks,
+            Command::Sessions,
         ];
         COMMANDS.iter()
     }
@@ -69,4 +139,22 @@ impl Command {
 pub fn print_progress(current:u64, total: u64) {
     let percent = current as f64 / total as f64;
     print!("\rProgress: {}B/{}B --- {:5.2}%     ",current,total,percent * 100 as f64);
-}
\ No newline at end of file
+}
+
+// Progress for payloads whose total size is not known up front
+pub fn print_received(current: u64) {
+    print!("\rProgress: {}B received     ",current);
+}
+
+// Splits the tokens of a command into its flags (starting with "--", or short ones like "-p") and the rest
+pub fn split_flags<'a>(tokens: &[&'a str]) -> (Vec<&'a str>, Vec<&'a str>) {
+    return tokens
+        .iter()
+        .partition(|token| token.starts_with("--") || (token.len() == 2 && token.starts_with('-')));
+}
+
+// Downloads are written to this path next to the destination first and only renamed over it when complete
+pub fn partial_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    return path.with_file_name(format!(".parfs-part-{}", file_name));
+}
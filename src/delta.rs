@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use sha2::{Digest, Sha256};
+
+use crate::message::BUFFER_SIZE;
+
+// rsync style delta transfer. The receiver describes the copy of the file it already has with a signature per
+// block, and the sender describes the new file as a sequence of literal data and references to those blocks.
+
+const MIN_BLOCK_SIZE: usize = 2048;
+const MAX_BLOCK_SIZE: usize = 131072;
+const STRONG_HASH_SIZE: usize = 16;
+// Literal data is flushed at this size so that the sender never buffers too much of the file
+const MAX_LITERAL_SIZE: usize = BUFFER_SIZE;
+
+// Operations of an encoded delta
+const OP_END: u8 = 0;
+const OP_COPY: u8 = 1;
+const OP_LITERAL: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; STRONG_HASH_SIZE],
+}
+
+#[derive(Debug, Default)]
+pub struct DeltaStats {
+    pub literal_bytes: u64,
+    pub copied_blocks: u64,
+}
+
+// Around the square root of the file size, which balances the size of the signatures against the size of the delta
+pub fn block_size_for(file_size: u64) -> usize {
+    return ((file_size as f64).sqrt() as usize).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE);
+}
+
+// Signatures of consecutive blocks of the receiver's copy
+pub fn compute_signatures<R: Read>(mut basis: R, block_size: usize) -> io::Result<Vec<BlockSignature>> {
+    let mut signatures: Vec<BlockSignature> = vec![];
+    let mut block = vec![0u8; block_size];
+    loop {
+        let length = read_full(&mut basis, &mut block)?;
+        if length == 0 {
+            break;
+        }
+        signatures.push(BlockSignature {
+            weak: RollingChecksum::new(&block[..length]).digest(),
+            strong: strong_hash(&block[..length]),
+        });
+        if length < block_size {
+            break;
+        }
+    }
+    return Ok(signatures);
+}
+
+// Signatures are sent as the block size followed by each signature, all big endian
+pub fn encode_signatures(block_size: usize, signatures: &[BlockSignature]) -> Vec<u8> {
+    let mut encoded: Vec<u8> = Vec::with_capacity(4 + signatures.len() * (4 + STRONG_HASH_SIZE));
+    encoded.extend((block_size as u32).to_be_bytes());
+    for signature in signatures {
+        encoded.extend(signature.weak.to_be_bytes());
+        encoded.extend(signature.strong);
+    }
+    return encoded;
+}
+
+pub fn decode_signatures(encoded: &[u8]) -> Option<(usize, Vec<BlockSignature>)> {
+    if encoded.len() < 4 || (encoded.len() - 4) % (4 + STRONG_HASH_SIZE) != 0 {
+        return None;
+    }
+    let block_size = u32::from_be_bytes(encoded[0..4].try_into().unwrap()) as usize;
+    if block_size == 0 {
+        return None;
+    }
+    let signatures: Vec<BlockSignature> = encoded[4..]
+        .chunks(4 + STRONG_HASH_SIZE)
+        .map(|chunk| BlockSignature {
+            weak: u32::from_be_bytes(chunk[0..4].try_into().unwrap()),
+            strong: chunk[4..].try_into().unwrap(),
+        })
+        .collect();
+    return Some((block_size, signatures));
+}
+
+// Describes source as literal data and references to blocks of the receiver's copy, and writes that to out
+pub fn write_delta<R: Read, W: Write>(
+    mut source: R,
+    block_size: usize,
+    signatures: &[BlockSignature],
+    out: &mut W,
+) -> io::Result<DeltaStats> {
+    let mut blocks_by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, signature) in signatures.iter().enumerate() {
+        blocks_by_weak.entry(signature.weak).or_default().push(index);
+    }
+
+    let mut stats = DeltaStats::default();
+    let mut buffer: Vec<u8> = vec![];
+    let mut end_of_source: bool = false;
+    // Start of the block being looked up, and start of the literal data not written yet
+    let mut position: usize = 0;
+    let mut literal_start: usize = 0;
+    let mut checksum: Option<RollingChecksum> = None;
+
+    loop {
+        // Drop what has been written already so the buffer stays small
+        if literal_start >= BUFFER_SIZE {
+            buffer.drain(..literal_start);
+            position -= literal_start;
+            literal_start = 0;
+        }
+        fill_buffer(&mut source, &mut buffer, position + block_size + 1, &mut end_of_source)?;
+        if buffer.len() < position + block_size {
+            break;
+        }
+
+        let window = &buffer[position..position + block_size];
+        let weak = checksum.get_or_insert_with(|| RollingChecksum::new(window)).digest();
+        let matching_block: Option<usize> = match blocks_by_weak.get(&weak) {
+            Some(candidates) => {
+                let strong = strong_hash(window);
+                candidates.iter().find(|index| signatures[**index].strong == strong).cloned()
+            }
+            None => None,
+        };
+
+        match matching_block {
+            Some(index) => {
+                stats.literal_bytes += write_literal(out, &buffer[literal_start..position])?;
+                out.write_all(&[OP_COPY])?;
+                out.write_all(&(index as u32).to_be_bytes())?;
+                stats.copied_blocks += 1;
+                position += block_size;
+                literal_start = position;
+                checksum = None;
+            }
+            None => {
+                // Slide the block along by one byte
+                if buffer.len() > position + block_size {
+                    let checksum = checksum.as_mut().unwrap();
+                    checksum.roll(buffer[position], buffer[position + block_size]);
+                } else {
+                    checksum = None;
+                }
+                position += 1;
+                if position - literal_start >= MAX_LITERAL_SIZE {
+                    stats.literal_bytes += write_literal(out, &buffer[literal_start..position])?;
+                    literal_start = position;
+                }
+            }
+        }
+    }
+
+    // Whatever is left is too short to be a block
+    fill_buffer(&mut source, &mut buffer, usize::MAX, &mut end_of_source)?;
+    stats.literal_bytes += write_literal(out, &buffer[literal_start..])?;
+    out.write_all(&[OP_END])?;
+    out.flush()?;
+    return Ok(stats);
+}
+
+// Rebuilds the sender's file from a delta and the receiver's copy basis
+pub fn apply_delta<R: Read, B: Read + Seek, W: Write>(
+    delta: &mut R,
+    basis: &mut B,
+    block_size: usize,
+    out: &mut W,
+) -> io::Result<()> {
+    loop {
+        let mut op: [u8; 1] = [0; 1];
+        delta.read_exact(&mut op)?;
+        match op[0] {
+            OP_END => break,
+            OP_COPY => {
+                let mut index: [u8; 4] = [0; 4];
+                delta.read_exact(&mut index)?;
+                let offset = u32::from_be_bytes(index) as u64 * block_size as u64;
+                basis.seek(SeekFrom::Start(offset))?;
+                let copied = io::copy(&mut basis.take(block_size as u64), out)?;
+                if copied == 0 {
+                    return Err(io::Error::new(ErrorKind::InvalidData, "Delta refers to a block that doesn't exist"));
+                }
+            }
+            OP_LITERAL => {
+                let mut length: [u8; 4] = [0; 4];
+                delta.read_exact(&mut length)?;
+                let length = u32::from_be_bytes(length) as u64;
+                let copied = io::copy(&mut delta.take(length), out)?;
+                if copied != length {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "Delta ended in the middle of literal data"));
+                }
+            }
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "Unknown delta operation")),
+        }
+    }
+    out.flush()?;
+    return Ok(());
+}
+
+fn write_literal<W: Write>(out: &mut W, literal: &[u8]) -> io::Result<u64> {
+    if literal.is_empty() {
+        return Ok(0);
+    }
+    out.write_all(&[OP_LITERAL])?;
+    out.write_all(&(literal.len() as u32).to_be_bytes())?;
+    out.write_all(literal)?;
+    return Ok(literal.len() as u64);
+}
+
+// Reads from source until buffer holds at least wanted bytes or source ends
+fn fill_buffer<R: Read>(source: &mut R, buffer: &mut Vec<u8>, wanted: usize, end_of_source: &mut bool) -> io::Result<()> {
+    let mut chunk = vec![0u8; BUFFER_SIZE];
+    while !*end_of_source && buffer.len() < wanted {
+        let length = source.read(&mut chunk)?;
+        if length == 0 {
+            *end_of_source = true;
+        }
+        buffer.extend_from_slice(&chunk[..length]);
+    }
+    return Ok(());
+}
+
+// Like read_exact, but a short read at the end of the reader is fine
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled: usize = 0;
+    while filled < buffer.len() {
+        let length = reader.read(&mut buffer[filled..])?;
+        if length == 0 {
+            break;
+        }
+        filled += length;
+    }
+    return Ok(filled);
+}
+
+fn strong_hash(block: &[u8]) -> [u8; STRONG_HASH_SIZE] {
+    let hash = Sha256::digest(block);
+    return hash[..STRONG_HASH_SIZE].try_into().unwrap();
+}
+
+// The weak checksum of rsync, which can be moved along the data one byte at a time
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        let length = block.len() as u32;
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((length - i as u32).wrapping_mul(*byte as u32));
+        }
+        Self { a, b, length }
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.a = self.a.wrapping_sub(outgoing as u32).wrapping_add(incoming as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.length.wrapping_mul(outgoing as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        return (self.a & 0xffff) | ((self.b & 0xffff) << 16);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Bytes that don't repeat within a block, so that every block of a basis can only be found where it belongs
+    fn pseudo_random(length: usize, seed: u32) -> Vec<u8> {
+        let mut state: u32 = seed;
+        return (0..length)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 24) as u8
+            })
+            .collect();
+    }
+
+    // Sends target as a delta against basis and returns what the receiver rebuilds with it
+    fn round_trip(basis: &[u8], target: &[u8], block_size: usize) -> (Vec<u8>, DeltaStats) {
+        let signatures = compute_signatures(basis, block_size).unwrap();
+        let (block_size, signatures) = decode_signatures(&encode_signatures(block_size, &signatures)).unwrap();
+        let mut delta: Vec<u8> = vec![];
+        let stats = write_delta(target, block_size, &signatures, &mut delta).unwrap();
+        let mut rebuilt: Vec<u8> = vec![];
+        apply_delta(&mut delta.as_slice(), &mut Cursor::new(basis), block_size, &mut rebuilt).unwrap();
+        return (rebuilt, stats);
+    }
+
+    #[test]
+    fn delta_rebuilds_edited_files() {
+        let block_size = 64;
+        let basis = pseudo_random(block_size * 20 + 10, 1);
+        let inserted = [&basis[..300], b"inserted".as_slice(), &basis[300..]].concat();
+        let deleted = [&basis[..300], &basis[400..]].concat();
+        let appended = [&basis[..], b"appended".as_slice()].concat();
+        let at_boundary = [&basis[..block_size * 5], b"boundary".as_slice(), &basis[block_size * 5..]].concat();
+        let cases: Vec<(&str, &[u8], &[u8], u64)> = vec![
+            ("unchanged", &basis, &basis, 20),
+            ("insert", &basis, &inserted, 19),
+            ("delete", &basis, &deleted, 17),
+            ("append", &basis, &appended, 20),
+            ("insert at a block boundary", &basis, &at_boundary, 20),
+            ("empty basis", b"", &basis, 0),
+            ("basis shorter than a block", &basis[..10], &appended, 0),
+            ("empty target", &basis, b"", 0),
+        ];
+        for (name, basis, target, min_copied_blocks) in cases {
+            let (rebuilt, stats) = round_trip(basis, target, block_size);
+            assert!(rebuilt == target, "{}: rebuilt {} of {} bytes", name, rebuilt.len(), target.len());
+            assert!(stats.copied_blocks >= min_copied_blocks, "{}: copied only {} blocks", name, stats.copied_blocks);
+        }
+    }
+
+    #[test]
+    fn delta_rebuilds_files_with_the_real_block_size() {
+        let basis = pseudo_random(100_000, 2);
+        let block_size = block_size_for(basis.len() as u64);
+        let target = [&basis[..5000], b"changed".as_slice(), &basis[5100..]].concat();
+        let (rebuilt, stats) = round_trip(&basis, &target, block_size);
+        assert!(rebuilt == target);
+        assert!(stats.literal_bytes < 2 * block_size as u64, "{} literal bytes", stats.literal_bytes);
+    }
+}
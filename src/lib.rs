@@ -1,4 +1,7 @@
 pub mod message;
 pub mod server;
 pub mod client;
-pub mod utilities;
\ No newline at end of file
+pub mod utilities;
+pub mod policy;
+pub mod manifest;
+pub mod delta;
\ No newline at end of file
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::message::BUFFER_SIZE;
+use crate::utilities::{decode_time, encode_time};
+
+// Describes a directory tree so that two copies of it can be compared, e.g. by sync.
+// Paths are relative to the root of the tree and always use "/" as separator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestEntry {
+    Directory,
+    File {
+        size: u64,
+        modified: SystemTime,
+        hash: String,
+    },
+}
+
+pub type Manifest = BTreeMap<String, ManifestEntry>;
+
+// Lists the paths of all directories and files below root, relative to root, skipping names that skip returns true for
+pub fn list_tree(root: &Path, skip: &dyn Fn(&str) -> bool) -> io::Result<Vec<(PathBuf, bool)>> {
+    let mut tree: Vec<(PathBuf, bool)> = vec![];
+    list_tree_into(root, Path::new(""), skip, &mut tree)?;
+    return Ok(tree);
+}
+
+fn list_tree_into(
+    root: &Path,
+    relative_dir: &Path,
+    skip: &dyn Fn(&str) -> bool,
+    tree: &mut Vec<(PathBuf, bool)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(root.join(relative_dir))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if skip(&name) {
+            continue;
+        }
+        let relative_path = relative_dir.join(&name);
+        // Symlinks are left out, they could point outside of the tree
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            tree.push((relative_path.clone(), true));
+            list_tree_into(root, &relative_path, skip, tree)?;
+        } else if file_type.is_file() {
+            tree.push((relative_path, false));
+        }
+    }
+    return Ok(());
+}
+
+// Describes the file at path
+pub fn file_entry(path: &Path) -> io::Result<ManifestEntry> {
+    let metadata = fs::metadata(path)?;
+    return Ok(ManifestEntry::File {
+        size: metadata.len(),
+        modified: metadata.modified()?,
+        hash: hash_file(path)?,
+    });
+}
+
+// SHA-256 of the content of a file as a hex string
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    return hash_reader(&mut File::open(path)?);
+}
+
+// SHA-256 of everything left in reader as a hex string
+pub fn hash_reader(reader: &mut dyn Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let length = reader.read(&mut buffer)?;
+        if length == 0 {
+            break;
+        }
+        hasher.update(&buffer[..length]);
+    }
+    return Ok(to_hex(&hasher.finalize()));
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+// Converts a relative path to the form used in manifests
+pub fn manifest_path(relative_path: &Path) -> String {
+    return relative_path
+        .iter()
+        .map(|component| component.to_string_lossy().to_string())
+        .collect::<Vec<String>>()
+        .join("/");
+}
+
+// One line per entry: "d\tpath" for directories, "f\tpath\tsize\tmtime\thash" for files
+pub fn encode_manifest(manifest: &Manifest) -> String {
+    return manifest
+        .iter()
+        .map(|(path, entry)| match entry {
+            ManifestEntry::Directory => format!("d\t{}", path),
+            ManifestEntry::File {
+                size,
+                modified,
+                hash,
+            } => format!("f\t{}\t{}\t{}\t{}", path, size, encode_time(*modified), hash),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+}
+
+pub fn decode_manifest(encoded: &str) -> Option<Manifest> {
+    let mut manifest = Manifest::new();
+    for line in encoded.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields[..] {
+            ["d", path] => {
+                manifest.insert(path.to_string(), ManifestEntry::Directory);
+            }
+            ["f", path, size, modified, hash] => {
+                let entry = ManifestEntry::File {
+                    size: size.parse().ok()?,
+                    modified: decode_time(modified)?,
+                    hash: hash.to_string(),
+                };
+                manifest.insert(path.to_string(), entry);
+            }
+            _ => return None,
+        }
+    }
+    return Some(manifest);
+}
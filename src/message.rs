@@ -1,6 +1,27 @@
 
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::time::SystemTime;
+
+use crate::utilities::{decode_time, encode_time};
+
 pub const HEADER_SIZE: usize = 13;
 pub const BUFFER_SIZE: usize = 1048576;
+// Message size sent in the headers when the payload size is not known up front. The payload is then sent in chunks,
+// each prefixed by its length as a big endian u32, and is terminated by an empty chunk.
+pub const CHUNKED_PAYLOAD: u64 = u64::MAX;
+// Chunk length that ends a CHUNKED_PAYLOAD early when the sender fails to produce the rest of it. The sender then
+// follows it with an Error message. Chunks are never longer than BUFFER_SIZE, so it can't be a real length.
+const ABORTED_CHUNK: u32 = u32::MAX;
+// Port sent instead of the port to connect to when the server can't take another client. An error message saying
+// why follows it.
+pub const SERVER_BUSY_PORT: i32 = 0;
+// Option of an Error reply refusing a request because another session kept a file busy, so it can be retried later
+pub const FILE_BUSY_OPTION: &str = "busy";
 
 // Refactor this rubbish with proper error handling, use custom types instead of io
 // https://www.sheshbabu.com/posts/rust-error-handling/
@@ -10,11 +31,30 @@ pub enum MessageKind {
     Login = 002,
     Success = 003,
     Error = 004,
+    Event = 005,
     Mkdir = 010,
     Cd = 020,
     Ls = 030,
+    Stat = 031,
+    Versions = 040,
+    Restore = 041,
+    Rm = 050,
+    Trash = 051,
+    Manifest = 060,
+    Watch = 070,
+    Unwatch = 071,
+    Lock = 080,
+    Unlock = 081,
+    Locks = 082,
+    Quota = 090,
+    Df = 091,
+    Sessions = 092,
     Up = 100,
+    UpExtract = 101,
+    Signature = 102,
+    Delta = 103,
     Down = 200,
+    DownTar = 201,
     File = 255,
 }
 
@@ -26,13 +66,274 @@ impl MessageKind {
             002 => MessageKind::Login,
             003 => MessageKind::Success,
             004 => MessageKind::Error,
+            005 => MessageKind::Event,
             010 => MessageKind::Mkdir,
             020 => MessageKind::Cd,
             030 => MessageKind::Ls,
+            031 => MessageKind::Stat,
+            040 => MessageKind::Versions,
+            041 => MessageKind::Restore,
+            050 => MessageKind::Rm,
+            051 => MessageKind::Trash,
+            060 => MessageKind::Manifest,
+            070 => MessageKind::Watch,
+            071 => MessageKind::Unwatch,
+            080 => MessageKind::Lock,
+            081 => MessageKind::Unlock,
+            082 => MessageKind::Locks,
+            090 => MessageKind::Quota,
+            091 => MessageKind::Df,
+            092 => MessageKind::Sessions,
             100 => MessageKind::Up,
+            101 => MessageKind::UpExtract,
+            102 => MessageKind::Signature,
+            103 => MessageKind::Delta,
             200 => MessageKind::Down,
+            201 => MessageKind::DownTar,
             255 => MessageKind::File,
             _ => panic!("Unable to parse messagekind - Unknown value: {}", value),
         }
     }
 }
+
+// Requests may carry options after their main argument, each on its own line as "key=value"
+pub fn add_option(arguments: &mut String, key: &str, value: &str) {
+    arguments.push('\n');
+    arguments.push_str(key);
+    arguments.push('=');
+    arguments.push_str(value);
+}
+
+// Splits arguments into the main argument and its options
+pub fn split_options(arguments: &str) -> (String, HashMap<String, String>) {
+    let mut lines = arguments.split('\n');
+    let main_argument: String = lines.next().unwrap_or("").to_string();
+    let options: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    return (main_argument, options);
+}
+
+// The mode bits that are kept with a file. The setuid, setgid and sticky bits aren't.
+const PERMISSION_BITS: u32 = 0o777;
+
+// Attributes of a file that can be sent along with it as options of its File message, so that the copy keeps them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub modified: Option<SystemTime>,
+    // Permission bits
+    pub mode: Option<u32>,
+}
+
+impl FileAttributes {
+    pub fn of(metadata: &fs::Metadata) -> Self {
+        return Self {
+            modified: metadata.modified().ok(),
+            mode: Some(metadata.mode() & PERMISSION_BITS),
+        };
+    }
+
+    pub fn from_options(options: &HashMap<String, String>) -> Self {
+        return Self {
+            modified: options.get("mtime").and_then(|mtime| decode_time(mtime)),
+            mode: options.get("mode").and_then(|mode| u32::from_str_radix(mode, 8).ok()).map(|mode| mode & PERMISSION_BITS),
+        };
+    }
+
+    // The owner can always read and write the file, so that the server can still send, version and trash it
+    pub fn with_owner_access(self) -> Self {
+        return Self {
+            mode: self.mode.map(|mode| mode | 0o600),
+            ..self
+        };
+    }
+
+    pub fn add_to(&self, arguments: &mut String) {
+        if let Some(modified) = self.modified {
+            add_option(arguments, "mtime", &encode_time(modified));
+        }
+        if let Some(mode) = self.mode {
+            add_option(arguments, "mode", &format!("{:o}", mode));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.modified.is_none() && self.mode.is_none();
+    }
+
+    // Sets the attributes on a file once everything has been written to it
+    pub fn apply(&self, file: &File) -> io::Result<()> {
+        if let Some(modified) = self.modified {
+            file.set_modified(modified)?;
+        }
+        if let Some(mode) = self.mode {
+            file.set_permissions(Permissions::from_mode(mode & PERMISSION_BITS))?;
+        }
+        return Ok(());
+    }
+}
+
+// Wraps a writer and frames everything written to it as chunks of a CHUNKED_PAYLOAD.
+// finish() must be called to send the terminating empty chunk, or abort() if the payload can't be completed.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+        }
+    }
+
+    // Sends whatever is still buffered followed by the terminating chunk, and hands back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.send_chunk()?;
+        self.inner.write_all(&0u32.to_be_bytes())?;
+        self.inner.flush()?;
+        return Ok(self.inner);
+    }
+
+    // Drops whatever is still buffered and tells the reader that the payload ends incomplete
+    pub fn abort(mut self) -> io::Result<W> {
+        self.buffer.clear();
+        self.inner.write_all(&ABORTED_CHUNK.to_be_bytes())?;
+        self.inner.flush()?;
+        return Ok(self.inner);
+    }
+
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk_length: u32 = self.buffer.len() as u32;
+        self.inner.write_all(&chunk_length.to_be_bytes())?;
+        self.inner.write_all(&self.buffer)?;
+        self.buffer.clear();
+        return Ok(());
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(BUFFER_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..length]);
+        if self.buffer.len() == BUFFER_SIZE {
+            self.send_chunk()?;
+        }
+        return Ok(length);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_chunk()?;
+        return self.inner.flush();
+    }
+}
+
+// Reads the payload of a CHUNKED_PAYLOAD message, stopping at the terminating empty chunk. A payload that the sender
+// aborted fails with PayloadAborted.
+// Never reads past the end of the payload, so the inner reader can be reused for the next message.
+pub struct ChunkedReader<R: Read> {
+    inner: R,
+    chunk_remaining: usize,
+    finished: bool,
+}
+
+impl<R: Read> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            chunk_remaining: 0,
+            finished: false,
+        }
+    }
+}
+
+impl<R: Read> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunk_remaining == 0 {
+            let mut chunk_header: [u8; 4] = [0; 4];
+            self.inner.read_exact(&mut chunk_header)?;
+            self.chunk_remaining = u32::from_be_bytes(chunk_header) as usize;
+            if self.chunk_remaining == 0 {
+                self.finished = true;
+                return Ok(0);
+            }
+            if self.chunk_remaining == ABORTED_CHUNK as usize {
+                self.chunk_remaining = 0;
+                self.finished = true;
+                return Err(io::Error::new(io::ErrorKind::Other, PayloadAborted));
+            }
+        }
+        let length = buf.len().min(self.chunk_remaining);
+        let bytes_read = self.inner.read(&mut buf[..length])?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed in the middle of a chunk",
+            ));
+        }
+        self.chunk_remaining -= bytes_read;
+        return Ok(bytes_read);
+    }
+}
+
+// The error of a CHUNKED_PAYLOAD that the sender couldn't complete. The connection is still usable, the Error message
+// saying why comes next.
+#[derive(Debug)]
+pub struct PayloadAborted;
+
+impl PayloadAborted {
+    // Whether e was caused by an aborted payload
+    pub fn of(e: &io::Error) -> bool {
+        return e.get_ref().is_some_and(|inner| inner.is::<PayloadAborted>());
+    }
+}
+
+impl fmt::Display for PayloadAborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("The sender aborted the payload")
+    }
+}
+
+impl error::Error for PayloadAborted {}
+
+// Reads exactly size bytes of a payload. Unlike Read::take, a connection closing early is reported as an error
+// instead of silently ending the payload.
+pub struct SizedReader<R: Read> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> SizedReader<R> {
+    pub fn new(inner: R, size: u64) -> Self {
+        Self {
+            inner,
+            remaining: size,
+        }
+    }
+}
+
+impl<R: Read> Read for SizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let length = (buf.len() as u64).min(self.remaining) as usize;
+        let bytes_read = self.inner.read(&mut buf[..length])?;
+        if bytes_read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before the whole payload was received",
+            ));
+        }
+        self.remaining -= bytes_read as u64;
+        return Ok(bytes_read);
+    }
+}
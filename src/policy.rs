@@ -0,0 +1,137 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// What to do when the destination of an upload or download already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverwritePolicy {
+    Fail,
+    Overwrite,
+    Skip,
+    Rename,
+    Newer,
+}
+
+// Outcome of applying an OverwritePolicy to a destination
+#[derive(Debug, PartialEq)]
+pub enum Resolution {
+    // Write to this path. It differs from the requested destination when renaming.
+    Write(PathBuf),
+    Skip,
+    Fail,
+}
+
+impl OverwritePolicy {
+    // Parses a command line flag of up / down
+    pub fn from_flag(flag: &str) -> Option<OverwritePolicy> {
+        match flag {
+            "--no-clobber" => Some(OverwritePolicy::Fail),
+            "--overwrite" => Some(OverwritePolicy::Overwrite),
+            "--skip" => Some(OverwritePolicy::Skip),
+            "--rename" => Some(OverwritePolicy::Rename),
+            "--newer" => Some(OverwritePolicy::Newer),
+            _ => None,
+        }
+    }
+
+    // Parses the value carried in a request
+    pub fn from_str(value: &str) -> Option<OverwritePolicy> {
+        match value {
+            "fail" => Some(OverwritePolicy::Fail),
+            "overwrite" => Some(OverwritePolicy::Overwrite),
+            "skip" => Some(OverwritePolicy::Skip),
+            "rename" => Some(OverwritePolicy::Rename),
+            "newer" => Some(OverwritePolicy::Newer),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverwritePolicy::Fail => "fail",
+            OverwritePolicy::Overwrite => "overwrite",
+            OverwritePolicy::Skip => "skip",
+            OverwritePolicy::Rename => "rename",
+            OverwritePolicy::Newer => "newer",
+        }
+    }
+
+    // Decides where an incoming file goes. source_modified is the modification time of the incoming file, which
+    // is only needed for OverwritePolicy::Newer. The caller must make sure destination can't change in between
+    // resolving and writing.
+    pub fn resolve(&self, destination: &Path, source_modified: Option<SystemTime>) -> Resolution {
+        return self.resolve_with(destination, source_modified, &|path| path.exists(), &local_modified);
+    }
+
+    // Like resolve, but whether a path is taken is decided by taken instead of by whether it exists, e.g. so that
+    // a file that is about to be created by someone else isn't chosen as well, and the modification time of the
+    // destination is looked up with modified, e.g. in the storage of the server
+    pub fn resolve_with(
+        &self,
+        destination: &Path,
+        source_modified: Option<SystemTime>,
+        taken: &dyn Fn(&Path) -> bool,
+        modified: &dyn Fn(&Path) -> Option<SystemTime>,
+    ) -> Resolution {
+        if !taken(destination) {
+            return Resolution::Write(destination.to_path_buf());
+        }
+        match self {
+            OverwritePolicy::Overwrite => Resolution::Write(destination.to_path_buf()),
+            OverwritePolicy::Fail => Resolution::Fail,
+            OverwritePolicy::Skip => Resolution::Skip,
+            OverwritePolicy::Rename => Resolution::Write(free_path(destination, taken)),
+            OverwritePolicy::Newer => {
+                if is_newer_than(source_modified, modified(destination)) {
+                    Resolution::Write(destination.to_path_buf())
+                } else {
+                    Resolution::Skip
+                }
+            }
+        }
+    }
+}
+
+impl Default for OverwritePolicy {
+    fn default() -> Self {
+        return OverwritePolicy::Overwrite;
+    }
+}
+
+// Checks if the incoming file is newer than the one at destination. An unknown time never counts as newer.
+pub fn is_newer(source_modified: Option<SystemTime>, destination: &Path) -> bool {
+    return is_newer_than(source_modified, local_modified(destination));
+}
+
+// Like is_newer, given the modification time of the destination, which is None if it doesn't exist
+pub fn is_newer_than(source_modified: Option<SystemTime>, destination_modified: Option<SystemTime>) -> bool {
+    let destination_modified = match destination_modified {
+        Some(modified) => modified,
+        None => return true,
+    };
+    match source_modified {
+        Some(source_modified) => source_modified > destination_modified,
+        None => false,
+    }
+}
+
+fn local_modified(path: &Path) -> Option<SystemTime> {
+    return fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+}
+
+// Finds the first of "name (1).ext", "name (2).ext", ... that is not taken yet
+fn free_path(destination: &Path, taken: &dyn Fn(&Path) -> bool) -> PathBuf {
+    let stem = destination.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = match destination.extension() {
+        Some(extension) => format!(".{}", extension.to_string_lossy()),
+        None => "".to_string(),
+    };
+    let mut suffix: u32 = 1;
+    loop {
+        let candidate = destination.with_file_name(format!("{} ({}){}", stem, suffix, extension));
+        if !taken(&candidate) {
+            return candidate;
+        }
+        suffix += 1;
+    }
+}
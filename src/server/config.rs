@@ -0,0 +1,109 @@
+use std::path::PathBuf;
+
+use crate::server::utilities::parse_size;
+
+// Settings of the server. They are given as flags after the address and home folder, e.g.
+// server 127.0.0.1:12800 ~/share --versions 5 --dedup
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // How many previous versions of a file are kept when it is overwritten. 0 disables versioning.
+    pub max_versions: usize,
+    // Days after which deleted entries are purged from the trash. 0 keeps them until the trash is emptied.
+    pub trash_days: u64,
+    // Whether file contents are stored once per distinct content, see BlobStore
+    pub dedup: bool,
+    // File listing the users that can log in and their quotas, see Users
+    pub users_file: Option<PathBuf>,
+    // Bytes of free space uploads may not use, so that the disk never fills up completely
+    pub reserve: u64,
+    // Seconds a session waits for a file another session is using before it is told the file is busy. 0 waits
+    // for as long as it takes.
+    pub lock_timeout: u64,
+    // Whether files are also locked with OS advisory locks, for other servers sharing the home folder
+    pub os_locks: bool,
+    // Users that may see the locks and sessions of everyone, given as a comma separated list
+    pub admins: Vec<String>,
+    // Workers kept running even when no client is connected, and the most that run at once. Each connected client
+    // takes up a worker for as long as it stays connected.
+    pub min_workers: usize,
+    pub max_workers: usize,
+    // Clients that may wait for a worker while max_workers are busy. Any more are told that the server is busy.
+    pub queue_length: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_versions: 0,
+            trash_days: 30,
+            dedup: false,
+            users_file: None,
+            reserve: 0,
+            lock_timeout: 30,
+            os_locks: false,
+            admins: Vec::new(),
+            min_workers: 2,
+            max_workers: 10,
+            queue_length: 20,
+        }
+    }
+}
+
+impl ServerConfig {
+    // Parses the flags following the address and home folder
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = ServerConfig::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            // Switches don't take a value
+            if flag == "--dedup" {
+                config.dedup = true;
+                continue;
+            }
+            if flag == "--os-locks" {
+                config.os_locks = true;
+                continue;
+            }
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("Missing value for {}", flag)),
+            };
+            match flag.as_str() {
+                "--versions" => config.max_versions = parse_number(flag, value)?,
+                "--trash-days" => config.trash_days = parse_number(flag, value)?,
+                "--users" => config.users_file = Some(PathBuf::from(value)),
+                "--reserve" => config.reserve = parse_size_flag(flag, value)?,
+                "--lock-timeout" => config.lock_timeout = parse_number(flag, value)?,
+                "--min-workers" => config.min_workers = parse_number(flag, value)?,
+                "--max-workers" => config.max_workers = parse_number(flag, value)?,
+                "--queue-length" => config.queue_length = parse_number(flag, value)?,
+                "--admins" => {
+                    config.admins = value.split(',').map(|admin| admin.trim().to_string()).filter(|admin| !admin.is_empty()).collect()
+                }
+                _ => return Err(format!("Unknown option {}", flag)),
+            }
+        }
+        if config.max_workers == 0 || config.min_workers > config.max_workers {
+            return Err(format!(
+                "Invalid workers: --max-workers must be at least 1 and at least --min-workers ({})",
+                config.min_workers
+            ));
+        }
+        return Ok(config);
+    }
+}
+
+// A number of bytes, which can end in K, M or G
+fn parse_size_flag(flag: &str, value: &str) -> Result<u64, String> {
+    match parse_size(value) {
+        Some(size) => Ok(size),
+        None => Err(format!("Invalid value for {}: {}", flag, value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(number) => Ok(number),
+        Err(_) => Err(format!("Invalid value for {}: {}", flag, value)),
+    }
+}
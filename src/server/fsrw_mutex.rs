@@ -1,63 +1,840 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock, Mutex, MutexGuard};
-use std::path::PathBuf;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant, SystemTime};
 
-// FileLock keeps track of which threads 
+use crate::server::os_lock::OsLock;
+use crate::server::sessions::{self, SessionState};
+use crate::server::storage::StorageBackend;
+use crate::server::utilities::{ERR_FILE_BEING_READ, ERR_FILE_BEING_WRITTEN};
+use crate::message::{add_option, FILE_BUSY_OPTION};
+use crate::utilities::format_error;
+
+// How a path is locked. Files and directories are locked alike: locking a path also takes the intention lock of
+// that mode on every ancestor, so that a directory can't be read or written as a whole while something inside of
+// it is being written, and nothing inside can be written while the directory is read or written as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockMode {
+    // Something inside is being read
+    IntentionShared,
+    // Something inside is being written
+    IntentionExclusive,
+    // Read, along with everything inside
+    Shared,
+    // Written, along with everything inside
+    Exclusive,
+}
+
+impl LockMode {
+    // The mode the ancestors of a path locked in this mode are locked in
+    fn intention(self) -> LockMode {
+        match self {
+            LockMode::IntentionShared | LockMode::Shared => LockMode::IntentionShared,
+            LockMode::IntentionExclusive | LockMode::Exclusive => LockMode::IntentionExclusive,
+        }
+    }
+
+    // How an admin is shown the mode
+    pub fn describe(self) -> &'static str {
+        match self {
+            LockMode::IntentionShared => "reading inside",
+            LockMode::IntentionExclusive => "writing inside",
+            LockMode::Shared => "reading",
+            LockMode::Exclusive => "writing",
+        }
+    }
+
+    fn is_writing(self) -> bool {
+        return self == LockMode::IntentionExclusive || self == LockMode::Exclusive;
+    }
+
+    // The weakest mode allowing both, for a path that is needed twice by one operation
+    fn combine(self, other: LockMode) -> LockMode {
+        match (self, other) {
+            (LockMode::IntentionExclusive, LockMode::Shared) | (LockMode::Shared, LockMode::IntentionExclusive) => {
+                LockMode::Exclusive
+            }
+            _ => self.max(other),
+        }
+    }
+}
+
+// Who holds the lock of a path, and who is waiting for it.
+// Writers are preferred: a reader doesn't get the lock while a writer it would block is waiting, so a steady stream
+// of readers can't keep a writer waiting forever. Readers still only wait for one writer at a time, as the readers
+// that are waiting when a writer is done get the lock before the next writer does.
+#[derive(Default)]
+struct Holders {
+    intention_shared: u32,
+    intention_exclusive: u32,
+    shared: u32,
+    exclusive: bool,
+    waiting_readers: u32,
+    waiting_shared: u32,
+    waiting_intention_exclusive: u32,
+    waiting_exclusive: u32,
+    // Waiting readers that go before waiting writers, because they were waiting when the last writer was done
+    readers_first: u32,
+    // Who holds the lock, for admins looking into a hanging transfer
+    held_by: Vec<Holding>,
+}
+
+struct Holding {
+    mode: LockMode,
+    thread: ThreadId,
+    // None for threads that don't run a session
+    session: Option<Arc<SessionState>>,
+    since: SystemTime,
+}
+
+// A holder of the lock of a path, see FsrwMutex::holdings
+pub struct LockHolder {
+    pub mode: LockMode,
+    pub session: Option<Arc<SessionState>>,
+    pub since: SystemTime,
+}
+
+// Who holds and waits for the lock of a path
+pub struct PathHoldings {
+    pub path: PathBuf,
+    pub holders: Vec<LockHolder>,
+    pub waiting: u32,
+}
+
+impl Holders {
+    // Whether mode can be held next to the current holders, and goes before whoever else is waiting. waited is
+    // whether the thread asking is one of those waiting.
+    fn grants(&self, mode: LockMode, waited: bool) -> bool {
+        if !self.allows(mode) {
+            return false;
+        }
+        let readers_first = waited && self.readers_first > 0;
+        match mode {
+            LockMode::IntentionShared => return readers_first || self.waiting_exclusive == 0,
+            LockMode::Shared => {
+                return readers_first || (self.waiting_exclusive == 0 && self.waiting_intention_exclusive == 0);
+            }
+            LockMode::IntentionExclusive => return self.readers_first == 0 || self.waiting_shared == 0,
+            LockMode::Exclusive => return self.readers_first == 0 || self.waiting_readers == 0,
+        }
+    }
+
+    fn allows(&self, mode: LockMode) -> bool {
+        match mode {
+            LockMode::IntentionShared => !self.exclusive,
+            LockMode::IntentionExclusive => !self.exclusive && self.shared == 0,
+            LockMode::Shared => !self.exclusive && self.intention_exclusive == 0,
+            LockMode::Exclusive => {
+                !self.exclusive && self.shared == 0 && self.intention_shared == 0 && self.intention_exclusive == 0
+            }
+        }
+    }
+
+    fn add(&mut self, mode: LockMode) {
+        match mode {
+            LockMode::IntentionShared => self.intention_shared += 1,
+            LockMode::IntentionExclusive => self.intention_exclusive += 1,
+            LockMode::Shared => self.shared += 1,
+            LockMode::Exclusive => self.exclusive = true,
+        }
+    }
+
+    fn remove(&mut self, mode: LockMode) {
+        match mode {
+            LockMode::IntentionShared => self.intention_shared -= 1,
+            LockMode::IntentionExclusive => self.intention_exclusive -= 1,
+            LockMode::Shared => self.shared -= 1,
+            LockMode::Exclusive => self.exclusive = false,
+        }
+        // The readers waiting for this writer go next
+        if mode.is_writing() {
+            self.readers_first = self.waiting_readers;
+        }
+    }
+
+    fn start_waiting(&mut self, mode: LockMode) {
+        match mode {
+            LockMode::IntentionShared => self.waiting_readers += 1,
+            LockMode::Shared => {
+                self.waiting_readers += 1;
+                self.waiting_shared += 1;
+            }
+            LockMode::IntentionExclusive => self.waiting_intention_exclusive += 1,
+            LockMode::Exclusive => self.waiting_exclusive += 1,
+        }
+    }
+
+    // granted is whether the thread stops waiting because it got the lock, rather than because it gave up
+    fn stop_waiting(&mut self, mode: LockMode, granted: bool) {
+        match mode {
+            LockMode::IntentionShared => self.waiting_readers -= 1,
+            LockMode::Shared => {
+                self.waiting_readers -= 1;
+                self.waiting_shared -= 1;
+            }
+            LockMode::IntentionExclusive => self.waiting_intention_exclusive -= 1,
+            LockMode::Exclusive => self.waiting_exclusive -= 1,
+        }
+        if !mode.is_writing() && granted {
+            self.readers_first = self.readers_first.saturating_sub(1);
+        }
+        self.readers_first = self.readers_first.min(self.waiting_readers);
+    }
+
+    // Whether the path or something inside of it is being written
+    fn writing(&self) -> bool {
+        return self.exclusive || self.intention_exclusive > 0;
+    }
+
+    fn waiting(&self) -> u32 {
+        return self.waiting_readers + self.waiting_intention_exclusive + self.waiting_exclusive;
+    }
+}
+
+// The lock of a file or directory. Its guards only hold an Arc to it, so unlike a std RwLock it can be locked
+// by guards that also keep its file_dict entry alive.
+struct PathLock {
+    path: PathBuf,
+    holders: Mutex<Holders>,
+    released: Condvar,
+}
+
+impl PathLock {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            holders: Mutex::new(Holders::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    // Holders is only changed by single updates that can't be left halfway, so it can be used as it is after a
+    // thread panicked while holding it
+    fn lock_holders(&self) -> MutexGuard<'_, Holders> {
+        match self.holders.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                println!("Recovering the lock of {:?} after a thread panicked", self.path);
+                self.holders.clear_poison();
+                poisoned.into_inner()
+            }
+        }
+    }
+
+    // Waits until mode is granted, or fails with FileBusy once deadline has passed
+    fn lock(&self, mode: LockMode, deadline: Option<Instant>) -> io::Result<()> {
+        let mut holders = self.lock_holders();
+        if !holders.grants(mode, false) {
+            holders.start_waiting(mode);
+            while !holders.grants(mode, true) {
+                holders = match self.wait(holders, deadline) {
+                    Ok(holders) => holders,
+                    Err(mut holders) => {
+                        holders.stop_waiting(mode, false);
+                        // Readers that were only waiting for this thread can go now
+                        self.released.notify_all();
+                        // Readers are only ever kept waiting by writers
+                        let writing = !mode.is_writing() || holders.writing();
+                        let busy = FileBusy {path: self.path.clone(), writing};
+                        return Err(io::Error::new(ErrorKind::ResourceBusy, busy));
+                    }
+                };
+            }
+            holders.stop_waiting(mode, true);
+        }
+        holders.add(mode);
+        holders.held_by.push(Holding {mode, thread: thread::current().id(), session: sessions::current(), since: SystemTime::now()});
+        return Ok(());
+    }
+
+    fn release(&self, mode: LockMode) {
+        let mut holders = self.lock_holders();
+        holders.remove(mode);
+        let thread = thread::current().id();
+        let released = holders
+            .held_by
+            .iter()
+            .position(|holding| holding.mode == mode && holding.thread == thread)
+            .or_else(|| holders.held_by.iter().position(|holding| holding.mode == mode));
+        if let Some(released) = released {
+            holders.held_by.remove(released);
+        }
+        self.released.notify_all();
+    }
+
+    fn holdings(&self) -> PathHoldings {
+        let holders = self.lock_holders();
+        return PathHoldings {
+            path: self.path.clone(),
+            holders: holders
+                .held_by
+                .iter()
+                .map(|holding| LockHolder {mode: holding.mode, session: holding.session.clone(), since: holding.since})
+                .collect(),
+            waiting: holders.waiting(),
+        };
+    }
+
+    fn is_writing(&self) -> bool {
+        return self.lock_holders().writing();
+    }
+
+    fn waiting(&self) -> u32 {
+        return self.lock_holders().waiting();
+    }
+
+    // Waits until the lock is released by a thread, but not past deadline. Err once deadline has passed.
+    fn wait<'a>(
+        &self,
+        holders: MutexGuard<'a, Holders>,
+        deadline: Option<Instant>,
+    ) -> Result<MutexGuard<'a, Holders>, MutexGuard<'a, Holders>> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => {
+                return match self.released.wait(holders) {
+                    Ok(guard) => Ok(guard),
+                    Err(poisoned) => {
+                        println!("Recovering the lock of {:?} after a thread panicked", self.path);
+                        self.holders.clear_poison();
+                        Ok(poisoned.into_inner())
+                    }
+                };
+            }
+        };
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(holders);
+        }
+        match self.released.wait_timeout(holders, deadline - now) {
+            Ok((guard, _)) => Ok(guard),
+            Err(poisoned) => {
+                println!("Recovering the lock of {:?} after a thread panicked", self.path);
+                self.holders.clear_poison();
+                Ok(poisoned.into_inner().0)
+            }
+        }
+    }
+}
+
+// The error of a lock that couldn't be taken in time because another session kept the file busy
+#[derive(Debug)]
+pub struct FileBusy {
+    pub path: PathBuf,
+    // Whether the file, or something inside the directory, was being written, otherwise it was being read
+    pub writing: bool,
+}
+
+impl FileBusy {
+    // The FileBusy that caused e, if any
+    pub fn of(e: &io::Error) -> Option<&FileBusy> {
+        return e.get_ref()?.downcast_ref::<FileBusy>();
+    }
+
+    // The reply to a session that is kept waiting. It is marked as such, so the client can tell it to try again.
+    pub fn message(&self) -> String {
+        let file_name = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let mut message = match self.writing {
+            true => format_error(ERR_FILE_BEING_WRITTEN, &file_name),
+            false => format_error(ERR_FILE_BEING_READ, &file_name),
+        };
+        add_option(&mut message, FILE_BUSY_OPTION, "1");
+        return message;
+    }
+}
+
+impl fmt::Display for FileBusy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let doing = if self.writing { "written" } else { "read" };
+        write!(f, "{:?} is busy, being {} by another session", self.path, doing)
+    }
+}
+
+impl error::Error for FileBusy {}
+
+// FileLock keeps track of which threads are accessing a file or directory
 pub struct FileLock {
     pub threads_accessing: i32,
-    pub lock: Arc<RwLock<PathBuf>>,
+    lock: Arc<PathLock>,
 }
 
-// File System Reader Writer Mutex controls access to the dict keeping track of which files are currently being accessed
-// and the corresponding rwlock to a file.
+// What threads are doing with the lock of a file or directory
+#[derive(Debug, Clone, Copy)]
+pub struct LockState {
+    // Threads holding or waiting for the lock
+    pub threads_accessing: i32,
+    // Whether one of them is writing it, or something inside of it
+    pub writing: bool,
+    // Those of them that wait for the lock
+    pub waiting: u32,
+}
+
+// File System Reader Writer Mutex controls access to the dict keeping track of which files and directories are
+// currently being accessed and the corresponding lock of each.
+// Paths are locked through the guards returned by read, write and lock, which unlock them and release their entries
+// in the dict when they are dropped, also when a transfer fails halfway or the thread panics. A thread panicking
+// while it holds the dict itself doesn't keep other threads from using it either.
+// All paths an operation needs, including the ancestors it takes intention locks on, are locked at once in the
+// order of their paths, so parents before their children. As every thread locks in that same order, no two threads
+// can each hold a path the other is waiting for.
+// Threads wait for a path for at most timeout, after which locking it fails with a FileBusy error.
+// With os_locks, files read or written are also locked with OS advisory locks once they are locked here, see OsLock.
+// Paths are normalized against storage, see normalize.
 pub struct FsrwMutex {
-    pub file_dict: Mutex<HashMap<PathBuf,FileLock>>,
+    file_dict: Mutex<HashMap<PathBuf, FileLock>>,
+    storage: Arc<dyn StorageBackend>,
+    // None waits for as long as it takes
+    timeout: Option<Duration>,
+    os_locks: bool,
 }
 
 impl FsrwMutex {
-    pub fn new () -> Self {
-        return Self {file_dict: Mutex::new(HashMap::new())};
+    pub fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        return Self::with_timeout(storage, None);
+    }
+
+    pub fn with_timeout(storage: Arc<dyn StorageBackend>, timeout: Option<Duration>) -> Self {
+        return Self {file_dict: Mutex::new(HashMap::new()), storage, timeout, os_locks: false};
+    }
+
+    // Also takes OS advisory locks on the files read and written, so that other processes see them. Only works
+    // with storage in the local filesystem.
+    pub fn with_os_locks(mut self, os_locks: bool) -> Self {
+        self.os_locks = os_locks;
+        return self;
+    }
+
+    // Blocks until file_path can be read. Fails if file_path can't be locked, e.g. because its folder is gone,
+    // or with FileBusy if it is written for longer than the timeout. file_path doesn't have to exist, so a file
+    // can be locked before it is created.
+    pub fn read(&self, file_path: PathBuf) -> io::Result<FileReadGuard<'_>> {
+        let deadline = self.deadline();
+        let file_dict = self.lock_dict();
+        let path = self.normalize(&file_path)?;
+        let locks = self.lock_all(file_dict, &[(path.clone(), LockMode::Shared)], deadline)?;
+        let os_lock = self.os_lock(&path, false, deadline)?;
+        return Ok(FileReadGuard {path, _os_lock: os_lock, _locks: locks});
+    }
+
+    // Blocks until file_path can be written. Fails if file_path can't be locked, e.g. because its folder is gone,
+    // or with FileBusy if it is read or written for longer than the timeout.
+    pub fn write(&self, file_path: PathBuf) -> io::Result<FileWriteGuard<'_>> {
+        let deadline = self.deadline();
+        let file_dict = self.lock_dict();
+        let path = self.normalize(&file_path)?;
+        let locks = self.lock_all(file_dict, &[(path.clone(), LockMode::Exclusive)], deadline)?;
+        let os_lock = self.os_lock(&path, true, deadline)?;
+        return Ok(FileWriteGuard {path, _os_lock: os_lock, _locks: locks});
+    }
+
+    // Like write, but the path is chosen by choose while file_dict is locked, so that no other thread can create
+    // or lock it in between. choose is given a check of whether a path exists or is locked, which also covers
+    // files that other threads are about to create. Nothing is locked if choose returns Err, which is passed on.
+    // The paths of also are locked along with the chosen one, e.g. the source of a move to it.
+    pub fn write_chosen<T>(
+        &self,
+        also: &[(PathBuf, LockMode)],
+        choose: impl FnOnce(&dyn Fn(&Path) -> bool) -> Result<PathBuf, T>,
+    ) -> io::Result<Result<FileWriteGuard<'_>, T>> {
+        let deadline = self.deadline();
+        let file_dict = self.lock_dict();
+        let taken = |path: &Path| -> bool {
+            return self.storage.exists(path) || self.normalize(path).map(|path| file_dict.contains_key(&path)).unwrap_or(false);
+        };
+        let file_path = match choose(&taken) {
+            Ok(file_path) => file_path,
+            Err(choice) => return Ok(Err(choice)),
+        };
+        let path = self.normalize(&file_path)?;
+        let mut normalized: Vec<(PathBuf, LockMode)> = vec![(path.clone(), LockMode::Exclusive)];
+        for (also_path, mode) in also {
+            normalized.push((self.normalize(also_path)?, *mode));
+        }
+        let locks = self.lock_all(file_dict, &normalized, deadline)?;
+        let os_lock = self.os_lock(&path, true, deadline)?;
+        return Ok(Ok(FileWriteGuard {path, _os_lock: os_lock, _locks: locks}));
     }
+
+    // Blocks until each of paths can be locked in its mode, e.g. a directory as Shared while it is listed, or both
+    // the source and the destination of a move as Exclusive. The paths don't have to exist.
+    pub fn lock(&self, paths: &[(PathBuf, LockMode)]) -> io::Result<PathLocks<'_>> {
+        let deadline = self.deadline();
+        let file_dict = self.lock_dict();
+        let mut normalized: Vec<(PathBuf, LockMode)> = vec![];
+        for (path, mode) in paths {
+            normalized.push((self.normalize(path)?, *mode));
+        }
+        return self.lock_all(file_dict, &normalized, deadline);
+    }
+
+    // Keeps directory itself from being removed or replaced while it is listed. This doesn't wait for changes to the
+    // entries of directory: files in it can still be written and directories created in it, which a listing sees
+    // either before or after as uploads only rename complete files into place and creating a directory is atomic.
+    pub fn lock_listing(&self, directory: PathBuf) -> io::Result<PathLocks<'_>> {
+        return self.lock(&[(directory, LockMode::IntentionShared)]);
+    }
+
+    // The path locks are keyed on, which is the same whether or not the path exists: the canonicalized path, where
+    // the part of it that doesn't exist yet is joined as it is to its deepest ancestor that does.
+    fn normalize(&self, path: &Path) -> io::Result<PathBuf> {
+        if self.storage.exists(path) {
+            return self.storage.canonicalize(path);
+        }
+        for existing in path.ancestors() {
+            if self.storage.exists(existing) {
+                let missing = path.strip_prefix(existing).unwrap();
+                return Ok(self.storage.canonicalize(existing)?.join(missing));
+            }
+        }
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("Cannot lock {:?}", path)));
+    }
+
+    // None if no thread is accessing path, which must be canonicalized
+    pub fn lock_state(&self, path: &Path) -> Option<LockState> {
+        let file_dict = self.lock_dict();
+        let file_lock = file_dict.get(path)?;
+        return Some(LockState {
+            threads_accessing: file_lock.threads_accessing,
+            writing: file_lock.lock.is_writing(),
+            waiting: file_lock.lock.waiting(),
+        });
+    }
+
+    // Taken after the locks of this process, and each path has an OS lock of its own, so threads of this process
+    // never wait for each other's OS locks
+    fn os_lock(&self, path: &Path, exclusive: bool, deadline: Option<Instant>) -> io::Result<Option<OsLock>> {
+        if !self.os_locks {
+            return Ok(None);
+        }
+        return OsLock::lock(path, exclusive, deadline).map(Some);
+    }
+
+    // Who holds and waits for the lock of each path that is being accessed, ordered by path
+    pub fn holdings(&self) -> Vec<PathHoldings> {
+        let locks: Vec<Arc<PathLock>> = self.lock_dict().values().map(|file_lock| Arc::clone(&file_lock.lock)).collect();
+        let mut holdings: Vec<PathHoldings> = locks.iter().map(|lock| lock.holdings()).collect();
+        holdings.sort_by(|a, b| a.path.cmp(&b.path));
+        return holdings;
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        return self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+
+    // Entries are only changed while file_dict is locked by single updates, which a panicking thread can't leave
+    // halfway, so file_dict can be used as it is after a thread panicked while holding it
+    fn lock_dict(&self) -> MutexGuard<'_, HashMap<PathBuf, FileLock>> {
+        match self.file_dict.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                println!("Recovering file_dict after a thread panicked");
+                self.file_dict.clear_poison();
+                poisoned.into_inner()
+            }
+        }
+    }
+
+    // Locks the normalized targets along with intention locks on their ancestors, in the order of their paths.
+    // file_dict is unlocked once every entry is acquired, before waiting for any of the locks.
+    fn lock_all(
+        &self,
+        mut file_dict: MutexGuard<HashMap<PathBuf, FileLock>>,
+        targets: &[(PathBuf, LockMode)],
+        deadline: Option<Instant>,
+    ) -> io::Result<PathLocks<'_>> {
+        let mut modes: BTreeMap<PathBuf, LockMode> = BTreeMap::new();
+        for (path, mode) in targets {
+            add_mode(&mut modes, path, *mode);
+            for ancestor in path.ancestors().skip(1) {
+                add_mode(&mut modes, ancestor, mode.intention());
+            }
+        }
+        let entries: Vec<(FileEntry, LockMode)> = modes
+            .into_iter()
+            .map(|(path, mode)| (self.acquire(&mut file_dict, path), mode))
+            .collect();
+        drop(file_dict);
+
+        // The guard unlocks whatever was locked if waiting for the next path fails
+        let mut locks = PathLocks {entries, locked: 0};
+        while locks.locked < locks.entries.len() {
+            let (entry, mode) = &locks.entries[locks.locked];
+            entry.lock().lock(*mode, deadline)?;
+            locks.locked += 1;
+        }
+        return Ok(locks);
+    }
+
+    // Returns the lock of path, creating its entry if no other thread is accessing it
+    fn acquire(&self, file_dict: &mut HashMap<PathBuf, FileLock>, path: PathBuf) -> FileEntry<'_> {
+        let file_lock = file_dict.entry(path.clone()).or_insert_with(|| FileLock {
+            threads_accessing: 0,
+            lock: Arc::new(PathLock::new(path)),
+        });
+        file_lock.threads_accessing += 1;
+        return FileEntry {fsrw_mutex: self, lock: Some(Arc::clone(&file_lock.lock))};
+    }
+
+    // The path may no longer exist, e.g. if the file was moved to the trash while it was locked, so the entry is
+    // looked up by the normalized path it was acquired with.
+    fn release(&self, lock: Arc<PathLock>) {
+        let mut file_dict = self.lock_dict();
+        let file_path = lock.path.clone();
+        drop(lock);
+        match file_dict.get_mut(&file_path) {
+            Some(file_lock) => {
+                file_lock.threads_accessing -= 1;
+                if file_lock.threads_accessing <= 0 {
+                    if Arc::strong_count(&file_lock.lock) != 1 {
+                        println!("Concurrency error: {:?} is released while threads still hold its lock", file_path);
+                    }
+                    file_dict.remove(&file_path);
+                };
+            },
+            None => {
+                // Nothing is left to release, which only happens if the entry was dropped early. It is rebuilt by
+                // the next thread that accesses the path.
+                println!("Concurrency error: {:?} was released already", file_path);
+            }
+        }
+    }
+}
+
+fn add_mode(modes: &mut BTreeMap<PathBuf, LockMode>, path: &Path, mode: LockMode) {
+    modes
+        .entry(path.to_path_buf())
+        .and_modify(|current| *current = current.combine(mode))
+        .or_insert(mode);
 }
 
-// If the file_path exists, return a rwlock pointing to that File.
-// Otherwise, create that File and return a rwlock pointing to it.
-// The path must be valid!
-pub fn acquire_file_rwlock<'a>(mut file_dict: MutexGuard<HashMap<PathBuf,FileLock>>, mut file_path: PathBuf) -> Arc<RwLock<PathBuf>> {
-    if !file_path.is_file() {
-        File::create(&file_path).expect("acquire_file_rwlock was provided with an invalid file_path.");
+
+// Counts a thread in on the entry of a path in file_dict for as long as it exists, whether or not it got the lock
+struct FileEntry<'a> {
+    fsrw_mutex: &'a FsrwMutex,
+    // Only None while being dropped
+    lock: Option<Arc<PathLock>>,
+}
+
+impl FileEntry<'_> {
+    fn lock(&self) -> &PathLock {
+        return self.lock.as_ref().unwrap();
     }
-    file_path = file_path.canonicalize().expect("acquire_file_rwlock was provided with an invalid file_path");
-    match file_dict.get_mut(&file_path) {
-        Some(file_lock) => {
-            file_lock.threads_accessing += 1;
-            return Arc::clone(&file_lock.lock);
-        },
-        None => {
-            
-            let rwlock = RwLock::new(file_path.clone());
-            let file_lock = FileLock{threads_accessing: 1,lock: Arc::new(rwlock)};
-            file_dict.insert(file_path.clone(), file_lock);
-            return Arc::clone(&file_dict.get(&file_path).unwrap().lock);
+}
+
+impl Drop for FileEntry<'_> {
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            self.fsrw_mutex.release(lock);
         }
     }
 }
 
-pub fn release_file_rwlock(mut file_dict: MutexGuard<HashMap<PathBuf,FileLock>>, mut file_path: PathBuf) {
-    file_path = file_path.canonicalize().expect("release_file_rwlock was provided with an invalid file_path");
-    match file_dict.get_mut(&file_path) {
-        Some(file_lock) => {
-            file_lock.threads_accessing -= 1;
-            if file_lock.threads_accessing == 0 {
-                // println!("{}",Arc::strong_count(&file_lock.lock));
-                assert!(Arc::strong_count(&file_lock.lock) == 1);
-                file_dict.remove(&file_path);
-            };
-        },
-        None => {
-            panic!("Concurrency error: thread is holding on to an invalid rwlock. file_path entry in file_dict has already been removed.");
+// Paths locked together, along with the intention locks on their ancestors
+pub struct PathLocks<'a> {
+    entries: Vec<(FileEntry<'a>, LockMode)>,
+    // How many of the entries, from the first, are locked
+    locked: usize,
+}
+
+impl Drop for PathLocks<'_> {
+    // The entries are released after this, when they are dropped
+    fn drop(&mut self) {
+        for (entry, mode) in self.entries[..self.locked].iter().rev() {
+            entry.lock().release(*mode);
+        }
+    }
+}
+
+// Read access to a file. Derefs to its canonicalized path.
+// The OS lock is released before the locks of this process, as fields are dropped in order.
+pub struct FileReadGuard<'a> {
+    path: PathBuf,
+    _os_lock: Option<OsLock>,
+    _locks: PathLocks<'a>,
+}
+
+impl Deref for FileReadGuard<'_> {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        return &self.path;
+    }
+}
+
+// Exclusive write access to a file. Derefs to its canonicalized path.
+pub struct FileWriteGuard<'a> {
+    path: PathBuf,
+    _os_lock: Option<OsLock>,
+    _locks: PathLocks<'a>,
+}
+
+impl Deref for FileWriteGuard<'_> {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        return &self.path;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::MemoryStorage;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    fn new_mutex(timeout: Option<Duration>) -> FsrwMutex {
+        return FsrwMutex::with_timeout(Arc::new(MemoryStorage::new()), timeout);
+    }
+
+    // The paths don't have to exist to be locked
+    fn test_path(name: &str) -> PathBuf {
+        return Path::new("/locks").join(name);
+    }
+
+    // Keeps threads taking turns on path until stop is set, so that it is held nearly all of the time
+    fn keep_busy(fsrw_mutex: &FsrwMutex, path: &Path, write: bool, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            if write {
+                let _guard = fsrw_mutex.write(path.to_path_buf()).unwrap();
+                thread::sleep(Duration::from_millis(2));
+            } else {
+                let _guard = fsrw_mutex.read(path.to_path_buf()).unwrap();
+                thread::sleep(Duration::from_millis(2));
+            }
+        }
+    }
+
+    // Spins until the lock of path is in a state that done accepts
+    fn wait_for(fsrw_mutex: &FsrwMutex, path: &Path, done: impl Fn(&LockState) -> bool) {
+        let path = fsrw_mutex.normalize(path).unwrap();
+        while !fsrw_mutex.lock_state(&path).is_some_and(|state| done(&state)) {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn listing_does_not_wait_for_uploads_inside() {
+        let fsrw_mutex = new_mutex(Some(Duration::from_millis(100)));
+        let directory = test_path("listed");
+        let upload = fsrw_mutex.write(directory.join("new.bin")).unwrap();
+        assert!(fsrw_mutex.lock_listing(directory.clone()).is_ok());
+
+        // Removing the directory has to wait for the upload though
+        let removal = fsrw_mutex.lock(&[(directory.clone(), LockMode::Exclusive)]).map(|_| ());
+        assert!(FileBusy::of(&removal.unwrap_err()).is_some());
+        drop(upload);
+        assert!(fsrw_mutex.lock(&[(directory, LockMode::Exclusive)]).is_ok());
+    }
+
+    #[test]
+    fn modes_of_one_call_are_combined() {
+        let fsrw_mutex = new_mutex(Some(Duration::from_millis(100)));
+        let directory = test_path("combined");
+        // The directory is both read and an ancestor of the file that is written, which takes it exclusively
+        let locks = fsrw_mutex.lock(&[(directory.clone(), LockMode::Shared), (directory.join("file"), LockMode::Exclusive)]);
+        assert!(locks.is_ok());
+        assert!(fsrw_mutex.lock_state(&fsrw_mutex.normalize(&directory).unwrap()).unwrap().writing);
+        assert!(fsrw_mutex.read(directory.join("other")).is_err());
+    }
+
+    #[test]
+    fn paths_locked_in_opposite_orders_do_not_deadlock() {
+        let fsrw_mutex = new_mutex(Some(Duration::from_secs(5)));
+        let first = test_path("order/first");
+        let second = test_path("order/second");
+        thread::scope(|scope| {
+            let forwards = [(first.clone(), LockMode::Exclusive), (second.clone(), LockMode::Exclusive)];
+            let backwards = [(second.clone(), LockMode::Exclusive), (first.clone(), LockMode::Exclusive)];
+            for paths in [forwards, backwards] {
+                let fsrw_mutex = &fsrw_mutex;
+                scope.spawn(move || {
+                    for _ in 0..200 {
+                        // Fails with FileBusy after the timeout if the threads wait for each other
+                        let _locks = fsrw_mutex.lock(&paths).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(fsrw_mutex.lock_state(&fsrw_mutex.normalize(&first).unwrap()).is_none());
+    }
+
+    #[test]
+    fn waiting_writer_blocks_new_readers() {
+        let fsrw_mutex = new_mutex(None);
+        let path = test_path("waiting-writer");
+        let order: Mutex<Vec<&str>> = Mutex::new(vec![]);
+        let reader = fsrw_mutex.read(path.clone()).unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let _guard = fsrw_mutex.write(path.clone()).unwrap();
+                order.lock().unwrap().push("writer");
+            });
+            wait_for(&fsrw_mutex, &path, |state| state.waiting == 1);
+            scope.spawn(|| {
+                let _guard = fsrw_mutex.read(path.clone()).unwrap();
+                order.lock().unwrap().push("new reader");
+            });
+
+            // The new reader could share the lock with the first one, but waits for the writer
+            wait_for(&fsrw_mutex, &path, |state| state.waiting == 2);
+            assert!(order.lock().unwrap().is_empty());
+            drop(reader);
+        });
+        assert_eq!(*order.lock().unwrap(), vec!["writer", "new reader"]);
+        assert!(fsrw_mutex.lock_state(&path).is_none());
+    }
+
+    #[test]
+    fn writer_is_not_starved_by_readers() {
+        let fsrw_mutex = new_mutex(Some(Duration::from_secs(5)));
+        let path = test_path("popular");
+        let stop = AtomicBool::new(false);
+        let mut waits: Vec<(bool, Duration)> = vec![];
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| keep_busy(&fsrw_mutex, &path, false, &stop));
+            }
+            // The lock is contended once several of them are accessing it
+            wait_for(&fsrw_mutex, &path, |state| state.threads_accessing >= 2);
+            for _ in 0..5 {
+                let started = Instant::now();
+                let locked = fsrw_mutex.write(path.clone()).is_ok();
+                waits.push((locked, started.elapsed()));
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+        for (locked, waited) in waits {
+            assert!(locked && waited < Duration::from_secs(1), "the writer waited {:?}", waited);
         }
     }
 
+    #[test]
+    fn reader_is_not_starved_by_writers() {
+        let fsrw_mutex = new_mutex(Some(Duration::from_secs(5)));
+        let path = test_path("busy");
+        let stop = AtomicBool::new(false);
+        let mut waits: Vec<(bool, Duration)> = vec![];
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| keep_busy(&fsrw_mutex, &path, true, &stop));
+            }
+            // The lock is contended once several of them are accessing it
+            wait_for(&fsrw_mutex, &path, |state| state.threads_accessing >= 2);
+            for _ in 0..5 {
+                let started = Instant::now();
+                let locked = fsrw_mutex.read(path.clone()).is_ok();
+                waits.push((locked, started.elapsed()));
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+        for (locked, waited) in waits {
+            assert!(locked && waited < Duration::from_secs(1), "the reader waited {:?}", waited);
+        }
+    }
 }
@@ -1,16 +1,27 @@
-use std::fs;
 use std::io::{self, Error, ErrorKind, Write};
 use std::net::{TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use crate::message::MessageKind;
-use crate::server::message::receiver::MessageReceiver;
+use crate::manifest::{encode_manifest, hash_reader, manifest_path, Manifest, ManifestEntry};
+use crate::message::{split_options, MessageKind, CHUNKED_PAYLOAD, SERVER_BUSY_PORT};
+use crate::policy::{OverwritePolicy, Resolution};
+use crate::server::message::receiver::{write_locked, DeltaBasis, ExtractOptions, MessageReceiver, TooLarge, WriteOptions};
 use crate::server::message::sender::MessageSender;
 use crate::server::utilities::*;
-use crate::utilities::format_error;
+use crate::utilities::{decode_time, format_error, format_time};
 
-use super::fsrw_mutex::FsrwMutex;
+use super::config::ServerConfig;
+use super::storage::{EntryKind, StorageBackend};
+use super::locks::{AdvisoryLocks, LockOwner};
+use super::fsrw_mutex::{FileBusy, FsrwMutex, LockMode};
+use super::sessions::{SessionState, Sessions};
+use super::trash::Trash;
+use super::usage::{Reservation, UsageLedger};
+use super::users::{QuotaReport, Usage, Users};
+use super::versions::VersionStore;
+use super::watch::{ChangeNotifier, DirectorySnapshot};
 
 pub struct ConnectionHandler {
     tcpstream: TcpStream,
@@ -19,11 +30,50 @@ pub struct ConnectionHandler {
     connection_dropped: bool,
     fsrw_mutex: Arc<FsrwMutex>,
     addr: String,
-    thread_id: usize,
+    // The session number, to tell the sessions apart in the logs
+    thread_id: u64,
+    versions: VersionStore,
+    trash: Trash,
+    storage: Arc<dyn StorageBackend>,
+    notifier: Arc<ChangeNotifier>,
+    locks: Arc<AdvisoryLocks>,
+    // Identifies this session for its advisory locks
+    session: u64,
+    users: Arc<Users>,
+    ledger: Arc<UsageLedger>,
+    // Free space that uploads must leave
+    reserve: u64,
+    // Sessions are anonymous until they log in
+    user: String,
+    // Users that may use the locks and sessions commands
+    admins: Vec<String>,
+    sessions: Arc<Sessions>,
+    // What this session is doing, for admins. Registered once the client is connected.
+    session_state: Option<Arc<SessionState>>,
 }
 
 // To do:: Have a proper way to indicate when the connection is dropped
 
+// Runs however the session ends, even if it panicked, so that it leaves neither its anonymous locks nor its entry in
+// the sessions behind
+impl Drop for ConnectionHandler {
+    fn drop(&mut self) {
+        self.locks.end_session(self.session);
+        if let Some(state) = &self.session_state {
+            self.sessions.end(state.id);
+        }
+    }
+}
+
+// Tells a client that connected while the server can't take it that it is busy, instead of the port to connect to.
+// waiting clients are ahead of it.
+pub fn reply_busy(stream: &TcpStream, waiting: usize, fsrw_mutex: &FsrwMutex, storage: &dyn StorageBackend) -> io::Result<()> {
+    let mut writer = stream;
+    writer.write_all(&SERVER_BUSY_PORT.to_le_bytes())?;
+    let busy_message = MessageSender::new(MessageKind::Error, format_error(ERR_SERVER_BUSY, &waiting.to_string()), None);
+    return busy_message.send_message(stream, fsrw_mutex, storage);
+}
+
 impl ConnectionHandler {
     //make a new connectionhandler which encapsulates the connection from the server's side! wow!
     pub fn new(
@@ -31,8 +81,18 @@ impl ConnectionHandler {
         home_directory: PathBuf,
         fsrw_mutex: Arc<FsrwMutex>,
         addr: String,
+        config: Arc<ServerConfig>,
+        storage: Arc<dyn StorageBackend>,
+        notifier: Arc<ChangeNotifier>,
+        locks: Arc<AdvisoryLocks>,
+        users: Arc<Users>,
+        ledger: Arc<UsageLedger>,
+        sessions: Arc<Sessions>,
     ) -> io::Result<Self> {
         println!("Server: New connection started");
+        let versions = VersionStore::new(&home_directory, config.max_versions, Arc::clone(&storage), Arc::clone(&ledger));
+        let trash = Trash::new(&home_directory, Arc::clone(&storage), Arc::clone(&ledger));
+        let session = locks.new_session();
         let handler = Self {
             tcpstream: stream,
             home_directory: home_directory.clone(),
@@ -40,16 +100,40 @@ impl ConnectionHandler {
             connection_dropped: false,
             fsrw_mutex,
             addr,
-            thread_id: 0,
+            thread_id: session,
+            versions,
+            trash,
+            storage,
+            notifier,
+            session,
+            locks,
+            users,
+            ledger,
+            reserve: config.reserve,
+            user: ANONYMOUS_USER.to_string(),
+            admins: config.admins.clone(),
+            sessions,
+            session_state: None,
         };
 
         return Ok(handler);
     }
 
     // main loop of the handler
-    pub fn handle_connection(mut self, port: usize) {
-        // Give thread the port as its id
-        self.thread_id = port;
+    pub fn handle_connection(mut self) {
+        // Listen on a new port that the OS picks, before the client is told to connect to it
+        let addr_split: Vec<&str> = self.addr.split(":").collect();
+        let ip_addr = addr_split[0];
+        let listener: TcpListener = match TcpListener::bind((ip_addr, 0)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("ID {}: Cannot listen for the connection: {}", self.thread_id, e);
+                self.exit();
+                return;
+            }
+        };
+        let port: u16 = listener.local_addr().map(|address| address.port()).unwrap_or(0);
+
         // Shift request to new port
         println!(
             "ID {}: Request now being shifted to port {}",
@@ -58,27 +142,22 @@ impl ConnectionHandler {
         let bytes: [u8; 4] = (port as i32).to_le_bytes();
         self.tcpstream.write(&bytes);
 
-        // Listen and capture incoming connection on new port
-        let addr_split: Vec<&str> = self.addr.split(":").collect();
-        let ip_addr = addr_split[0];
-        let new_addr: &str = &(ip_addr.to_string() + ":" + port.to_string().as_str());
-        println!(
-            "ID {}: New address to connect to: {}",
-            self.thread_id, new_addr
-        );
-        let listener: TcpListener = TcpListener::bind(new_addr).unwrap();
-
         for stream in listener.incoming() {
             self.tcpstream = stream.unwrap();
             break;
         }
+        let address = match self.tcpstream.peer_addr() {
+            Ok(address) => address.to_string(),
+            Err(_) => "unknown".to_string(),
+        };
+        self.session_state = Some(self.sessions.start(self.thread_id, address, &self.user, &self.current_directory));
 
         let welcome_message = MessageSender::new(
             MessageKind::Success,
             self.get_display_path(&self.current_directory),
             None,
         );
-        let final_msg_result = welcome_message.send_message(&self.tcpstream, &self.fsrw_mutex);
+        let final_msg_result = welcome_message.send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref());
 
         if let Err(e) = final_msg_result {
             println!("ID {}: {}", self.thread_id, e);
@@ -95,10 +174,14 @@ impl ConnectionHandler {
             }
             let client_request = client_request.unwrap();
 
-            // Confirms received request
+            // Confirms received request. The options of a login hold the password, so only the user name is logged.
+            let logged_arguments: String = match client_request.command {
+                MessageKind::Login => split_options(&client_request.arguments).0,
+                _ => client_request.arguments.clone(),
+            };
             println!(
                 "ID {}: Received request: {:?} {}",
-                self.thread_id, &client_request.command, &client_request.arguments
+                self.thread_id, &client_request.command, logged_arguments
             );
 
             // Group of match statements to process different commands
@@ -106,11 +189,27 @@ impl ConnectionHandler {
             let message_kind: MessageKind = client_request.command;
             let arguments: String = client_request.arguments;
             let result: Result<MessageSender, Error> = match message_kind {
+                MessageKind::Login => self.login(arguments),
+                MessageKind::Quota => self.quota(),
+                MessageKind::Df => self.df(),
                 MessageKind::Mkdir => self.mkdir(arguments),
                 MessageKind::Cd => self.cd(arguments),
                 MessageKind::Ls => self.ls(),
+                MessageKind::Stat => self.stat(arguments),
                 MessageKind::Down => self.down(arguments),
+                MessageKind::DownTar => self.down_tar(arguments),
                 MessageKind::Up => self.up(arguments),
+                MessageKind::UpExtract => self.up_extract(arguments),
+                MessageKind::Versions => self.list_versions(arguments),
+                MessageKind::Restore => self.restore(arguments),
+                MessageKind::Rm => self.rm(arguments),
+                MessageKind::Trash => self.trash(arguments),
+                MessageKind::Manifest => self.manifest(arguments),
+                MessageKind::Watch => self.watch(arguments),
+                MessageKind::Lock => self.lock(arguments),
+                MessageKind::Unlock => self.unlock(arguments),
+                MessageKind::Locks => self.list_locks(),
+                MessageKind::Sessions => self.list_sessions(),
                 //place holder
                 _ => Err(Error::new(
                     ErrorKind::Other,
@@ -118,25 +217,44 @@ impl ConnectionHandler {
                 )),
             };
 
+            // Lets sessions watching a directory look for changes right away
+            if let MessageKind::Mkdir
+            | MessageKind::Up
+            | MessageKind::UpExtract
+            | MessageKind::Restore
+            | MessageKind::Rm
+            | MessageKind::Trash = message_kind
+            {
+                self.notifier.notify();
+            }
+
             // Ok() will be the MessageSender created by the individual functions, be it Success / Error
             // Err() will be errors propagated by ? in other parts of the function
             let final_msg_result: Result<(), Error> = match result {
-                Ok(message) => message.send_message(&self.tcpstream, &self.fsrw_mutex),
+                Ok(message) => message.send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref()),
                 Err(e) => {
                     if e.kind() == ErrorKind::UnexpectedEof {
                         self.exit();
                         return;
                     }
                     println!("ID {}: {}", self.thread_id, e);
-                    let generic_server_err: String = "Server error: please try again.".to_string();
-                    let error_message: MessageSender = self.error_message(generic_server_err);
-                    error_message.send_message(&self.tcpstream, &self.fsrw_mutex)
+                    let error_message: MessageSender = match FileBusy::of(&e) {
+                        Some(busy) => self.error_message(busy.message()),
+                        None => {
+                            let generic_server_err: String = "Server error: please try again.".to_string();
+                            self.error_message(generic_server_err)
+                        }
+                    };
+                    error_message.send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref())
                 }
             };
 
             if let Err(e) = final_msg_result {
                 println!("ID {}: {}", self.thread_id, e);
             }
+            if let Some(state) = &self.session_state {
+                state.end_transfer();
+            }
         }
     }
 
@@ -149,6 +267,9 @@ impl ConnectionHandler {
         }
 
         let file_path = PathBuf::from(&self.current_directory).join(new_dir);
+        if self.is_hidden_name(&file_path) {
+            return Ok(self.error_message(format_error(ERR_NO_DIR, &dir_name)));
+        }
         if self.is_valid_file(&file_path) {
             return Ok(self.error_message(format_error(ERR_FILE_EXISTS, &dir_name)));
         } else {
@@ -160,7 +281,10 @@ impl ConnectionHandler {
                 )));
             }
         };
-        fs::create_dir(file_path)?;
+        // Waits for the path to stop being written or removed. Sessions listing the parent don't wait for this lock,
+        // they see the directory either before or after it is created as creating it is atomic.
+        let _locks = self.fsrw_mutex.lock(&[(file_path.clone(), LockMode::Exclusive)])?;
+        self.storage.create_dir(&file_path)?;
         return Ok(self.success_message(None));
     }
 
@@ -177,8 +301,11 @@ impl ConnectionHandler {
         new_path = Path::new(&self.current_directory).join(&new_path);
         if self.is_valid_directory(&new_path) {
             // Sends success message if path exists
-            new_path = Path::canonicalize(&Path::new(&self.current_directory).join(new_path))?;
+            new_path = self.storage.canonicalize(&Path::new(&self.current_directory).join(new_path))?;
             self.current_directory = new_path;
+            if let Some(state) = &self.session_state {
+                state.set_directory(&self.current_directory);
+            }
             return Ok(self.success_message(Some(self.get_display_path(&self.current_directory))));
         }
         // Sends error message if file does not exists
@@ -188,19 +315,23 @@ impl ConnectionHandler {
     }
 
     fn ls(&self) -> io::Result<MessageSender> {
-        let paths = fs::read_dir(&self.current_directory)?;
+        // Files being uploaded don't show up until they are complete and directories are created atomically, so
+        // only the directory itself is locked
+        let locks = self.fsrw_mutex.lock_listing(self.current_directory.clone())?;
+        let entries = self.storage.list(&self.current_directory)?;
+        drop(locks);
 
-        // Joins the paths in the the iterator paths with "\n". If the entry is a directory, append a "/" to the end
-        let output: String = paths
+        // Joins the entries with "\n". If the entry is a directory, append a "/" to the end
+        let output: String = entries
             .into_iter()
-            .map(|x| -> String {
-                let path = x.unwrap().path();
-                if path.is_dir() {
-                    let mut new_str = String::from(path.iter().last().unwrap().to_str().unwrap());
+            .filter(|entry| !is_hidden(&entry.name))
+            .map(|entry| -> String {
+                if entry.kind == EntryKind::Directory {
+                    let mut new_str = entry.name;
                     new_str.push_str("/");
                     new_str
                 } else {
-                    String::from(path.iter().last().unwrap().to_str().unwrap())
+                    entry.name
                 }
             })
             .collect::<Vec<String>>()
@@ -209,11 +340,85 @@ impl ConnectionHandler {
         return Ok(self.success_message(Some(output)));
     }
 
+    // Describes a file, directory or symlink, one "field\tvalue" line per field
+    fn stat(&self, path_name: String) -> io::Result<MessageSender> {
+        let path: PathBuf = PathBuf::from(&self.current_directory).join(&path_name);
+        // Symlinks aren't followed, so they are found through their parent directory
+        let path: PathBuf = if self.is_valid_file(&path) || self.is_valid_directory(&path) {
+            self.storage.canonicalize(&path)?
+        } else {
+            match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) if !is_hidden(&name.to_string_lossy()) && self.is_valid_directory(&parent.to_path_buf()) => {
+                    self.storage.canonicalize(parent)?.join(name)
+                }
+                _ => return Ok(self.error_message(format_error(ERR_NO_ENTRY, &path_name))),
+            }
+        };
+        let details = match self.storage.details(&path) {
+            Ok(details) => details,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(self.error_message(format_error(ERR_NO_ENTRY, &path_name))),
+            Err(e) => return Err(e),
+        };
+
+        let kind = match details.metadata.kind {
+            EntryKind::File => "file",
+            EntryKind::Directory => "directory",
+            EntryKind::Other if details.symlink_target.is_some() => "symlink",
+            EntryKind::Other => "other",
+        };
+        let mut display_path = self.get_display_path(&path);
+        if !details.metadata.is_dir() {
+            display_path = display_path.trim_end_matches('/').to_string();
+        }
+        let mut fields: Vec<(&str, String)> = vec![
+            ("Path", display_path),
+            ("Type", kind.to_string()),
+            ("Size", format!("{}B", details.metadata.size)),
+            ("Modified", format_time(details.metadata.modified)),
+        ];
+        if let Some(accessed) = details.accessed {
+            fields.push(("Accessed", format_time(accessed)));
+        }
+        if let Some(changed) = details.changed {
+            fields.push(("Changed", format_time(changed)));
+        }
+        if let Some(mode) = details.mode {
+            fields.push(("Mode", format!("{:04o}", mode)));
+        }
+        if let Some((uid, gid)) = details.owner {
+            fields.push(("Owner", format!("uid {}, gid {}", uid, gid)));
+        }
+        if let Some(owner) = self.ledger.owner(&path) {
+            fields.push(("Uploaded by", owner));
+        }
+        if let Some(target) = details.symlink_target {
+            fields.push(("Target", target.to_string_lossy().to_string()));
+        }
+        let access = match self.fsrw_mutex.lock_state(&path) {
+            Some(state) if state.writing => format!("being written, {} sessions accessing", state.threads_accessing),
+            Some(state) => format!("being read, {} sessions accessing", state.threads_accessing),
+            None => "not in use".to_string(),
+        };
+        fields.push(("Access", access));
+        if let Some(lock) = self.locks.get(&path) {
+            fields.push(("Lock", lock.to_string()));
+        }
+
+        let output: String = fields
+            .iter()
+            .map(|(field, value)| format!("{}\t{}", field, value))
+            .collect::<Vec<String>>()
+            .join("\n");
+        return Ok(self.success_message(Some(output)));
+    }
+
     fn down(&self, file_name: String) -> io::Result<MessageSender> {
         let mut file_path: PathBuf = PathBuf::from(&self.current_directory);
         file_path.push(file_name.as_str());
         println!("ID {}: {}", self.thread_id, file_path.to_str().unwrap());
         if self.is_valid_file(&file_path) {
+            let size = self.storage.stat(&file_path)?.size;
+            self.start_transfer("down", &file_name, Some(size));
             let file_sender: MessageSender =
                 MessageSender::new(MessageKind::File, "".to_string(), Some(file_path));
 
@@ -223,11 +428,39 @@ impl ConnectionHandler {
         }
     }
 
+    // Sends the directory as a tar archive that is generated while it is being sent
+    fn down_tar(&self, dir_name: String) -> io::Result<MessageSender> {
+        let mut dir_path: PathBuf = PathBuf::from(&self.current_directory);
+        dir_path.push(dir_name.as_str());
+        if self.is_valid_directory(&dir_path) {
+            let dir_path = self.storage.canonicalize(&dir_path)?;
+            println!("ID {}: Archiving {:?}", self.thread_id, dir_path);
+            self.start_transfer("down", &dir_name, None);
+            return Ok(MessageSender::archive(MessageKind::File, "".to_string(), dir_path));
+        } else {
+            return Ok(self.error_message(format_error(ERR_NO_DIR, &dir_name)));
+        }
+    }
+
     // For the server to handle an up, it will first send a success to the client
     // to indicate that it is ready to receive a file.
-    fn up(&mut self, file_name: String) -> io::Result<MessageSender> {
+    // The request can carry an overwrite policy and the modification time of the client's file as options.
+    fn up(&mut self, arguments: String) -> io::Result<MessageSender> {
+        let (file_name, options) = split_options(&arguments);
+        let policy: OverwritePolicy = match options.get("policy") {
+            Some(policy) => match OverwritePolicy::from_str(policy) {
+                Some(policy) => policy,
+                None => return Ok(self.error_message(format_error(ERR_INVALID_POLICY, policy))),
+            },
+            None => OverwritePolicy::default(),
+        };
+        let source_modified: Option<SystemTime> = options.get("mtime").and_then(|mtime| decode_time(mtime));
+
         let mut file_path: PathBuf = PathBuf::from(&self.current_directory);
         file_path.push(file_name.as_str());
+        if self.is_hidden_name(&file_path) {
+            return Ok(self.error_message(format_error(ERR_NO_PATH, &file_name)));
+        }
 
         // Check if file to be written to is a file. If not, check if the parent is a directory. If not, send an error message.
         if !self.is_valid_file(&file_path) {
@@ -240,21 +473,696 @@ impl ConnectionHandler {
             }
         }
 
+        // Refuse early so the client doesn't send a file that won't be written. A success with a non-empty
+        // message tells the client not to send the file. The policy is applied again under the write lock.
+        let write_path: PathBuf = match policy.resolve_with(
+            &file_path,
+            source_modified,
+            &|path| self.storage.exists(path),
+            &|path| self.storage.stat(path).ok().map(|metadata| metadata.modified),
+        ) {
+            Resolution::Fail => return Ok(self.error_message(format_error(ERR_NOT_OVERWRITTEN, &file_name))),
+            Resolution::Skip => return Ok(self.success_message(Some(format_error(MSG_SKIPPED, &file_name)))),
+            Resolution::Write(write_path) => write_path,
+        };
+        if let Some(locked) = self.locked_by_other(&write_path)? {
+            return Ok(locked);
+        }
+        // The client announces the size of the file, so that it doesn't send a file that won't fit
+        // It stays reserved in the user's quota until the upload is recorded or has failed, so that other
+        // sessions of theirs can't take the same room
+        let announced_size: u64 = options.get("size").and_then(|size| size.parse().ok()).unwrap_or(0);
+        let mut reservation = match self.reserve_upload(&file_name, Some(&write_path), announced_size, 1)? {
+            Ok(reservation) => reservation,
+            Err(refused) => return Ok(refused),
+        };
+
+        // For a delta upload over an existing file the client is sent the signatures of that file instead of a
+        // success, and answers with a delta rather than the whole file
+        let basis: Option<DeltaBasis> = if options.contains_key("delta") && write_path == file_path && self.storage.is_file(&file_path) {
+            Some(DeltaBasis::read(file_path.clone(), &self.fsrw_mutex, self.storage.as_ref())?)
+        } else {
+            None
+        };
+
         println!("ID {}: Ready to receive {:?}", self.thread_id, file_path);
+        match &basis {
+            Some(basis) => MessageSender::with_payload(MessageKind::Signature, "".to_string(), basis.encode())
+                .send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref())?,
+            None => self.success_message(None)
+                .send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref())?,
+        }
+        let file_message = match self.receive_message() {
+            Some(message) => message,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed")),
+        };
+        // The actual size is checked again before anything is written, in case less was announced
+        let size_known = file_message.command == MessageKind::File && file_message.payload_size != CHUNKED_PAYLOAD;
+        if size_known {
+            if let Some(refused) = self.resize_upload(&mut reservation, &file_name, Some(&write_path), file_message.payload_size, 1)? {
+                file_message.discard(&self.tcpstream)?;
+                return Ok(refused);
+            }
+        }
+        self.start_transfer("up", &file_name, Self::payload_size(&file_message));
+        // Nothing tells how large a delta or a chunked payload gets, so the limit is enforced while writing too
+        let write_options = WriteOptions {
+            policy,
+            source_modified,
+            attributes: file_message.attributes(),
+            max_bytes: self.upload_limit(&mut reservation, Some(&write_path), size_known)?,
+        };
+        let write_result: io::Result<Resolution> = match (&file_message.command, &basis) {
+            (MessageKind::File, _) => file_message.write_to(
+                &self.tcpstream,
+                file_path.clone(),
+                &self.fsrw_mutex,
+                write_options,
+                &self.versions,
+                self.storage.as_ref(),
+            ),
+            (MessageKind::Delta, Some(basis)) => match file_message.apply_delta_to(
+                &self.tcpstream,
+                basis,
+                &self.fsrw_mutex,
+                write_options,
+                &self.versions,
+                self.storage.as_ref(),
+            ) {
+                Ok(Some(resolution)) => Ok(resolution),
+                Ok(None) => return Ok(self.error_message(format_error(ERR_BASIS_CHANGED, &file_name))),
+                Err(e) => Err(e),
+            },
+            _ => return self.unexpected_message(file_message),
+        };
+        let resolution: Resolution = match write_result {
+            Ok(resolution) => resolution,
+            Err(e) => match TooLarge::of(&e) {
+                Some(too_large) => return Ok(self.too_large(&file_name, too_large)),
+                None => return Err(e),
+            },
+        };
+        match resolution {
+            Resolution::Write(write_path) => {
+                self.ledger.record(&self.storage.canonicalize(&write_path)?, &self.user)?;
+                drop(reservation);
+                if write_path != file_path {
+                    let saved_name = write_path.file_name().unwrap().to_string_lossy().to_string();
+                    return Ok(self.success_message(Some(format_error(MSG_RENAMED, &saved_name))));
+                }
+                return Ok(self.success_message(None));
+            }
+            Resolution::Skip => return Ok(self.success_message(Some(format_error(MSG_SKIPPED, &file_name)))),
+            Resolution::Fail => return Ok(self.error_message(format_error(ERR_NOT_OVERWRITTEN, &file_name))),
+        }
+    }
+
+    // Receives a tar archive and unpacks it into an existing directory while it is being received. The request can
+    // carry an overwrite policy for the entries as an option.
+    fn up_extract(&mut self, arguments: String) -> io::Result<MessageSender> {
+        let (dir_name, options) = split_options(&arguments);
+        let policy: OverwritePolicy = match options.get("policy") {
+            Some(policy) => match OverwritePolicy::from_str(policy) {
+                Some(policy) => policy,
+                None => return Ok(self.error_message(format_error(ERR_INVALID_POLICY, policy))),
+            },
+            None => OverwritePolicy::default(),
+        };
+        let mut dir_path: PathBuf = PathBuf::from(&self.current_directory);
+        dir_path.push(dir_name.as_str());
+        if !self.is_valid_directory(&dir_path) {
+            return Ok(self.error_message(format_error(ERR_NO_DIR, &dir_name)));
+        }
+        let dir_path = self.storage.canonicalize(&dir_path)?;
+        // How many files the archive holds isn't known up front, so only its size is checked
+        let announced_size: u64 = options.get("size").and_then(|size| size.parse().ok()).unwrap_or(0);
+        let mut reservation = match self.reserve_upload(&dir_name, None, announced_size, 0)? {
+            Ok(reservation) => reservation,
+            Err(refused) => return Ok(refused),
+        };
+
+        println!("ID {}: Ready to extract into {:?}", self.thread_id, dir_path);
         self.success_message(None)
-            .send_message(&self.tcpstream, &self.fsrw_mutex)?;
+            .send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref())?;
         let file_message = match self.receive_message() {
             Some(message) => message,
             None => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed")),
         };
         if file_message.command != MessageKind::File {
-            panic!("Received wrong message kind from client!");
+            return self.unexpected_message(file_message);
+        }
+        let size_known = file_message.payload_size != CHUNKED_PAYLOAD;
+        if size_known {
+            if let Some(refused) = self.resize_upload(&mut reservation, &dir_name, None, file_message.payload_size, 0)? {
+                file_message.discard(&self.tcpstream)?;
+                return Ok(refused);
+            }
+        }
+        self.start_transfer("up", &dir_name, Self::payload_size(&file_message));
+        // Entries that someone else locked are rejected like the ones that are busy, and so are those that don't
+        // fit in what is left of the quota and the free space
+        let locked = |path: &Path| -> io::Result<bool> { return Ok(self.locked_by_other(path)?.is_some()) };
+        let extract_options = ExtractOptions {
+            locked: &locked,
+            max_bytes: self.upload_limit(&mut reservation, None, size_known)?,
+            policy,
+        };
+        let (written, rejected) = file_message.extract_to(
+            &self.tcpstream,
+            dir_path,
+            &self.fsrw_mutex,
+            extract_options,
+            &self.versions,
+            self.storage.as_ref(),
+        )?;
+        for file_path in written {
+            self.ledger.record(&self.storage.canonicalize(&file_path)?, &self.user)?;
+        }
+        drop(reservation);
+        if !rejected.is_empty() {
+            return Ok(self.error_message(format_error(ERR_ARCHIVE_ENTRIES, &rejected.join(", "))));
+        }
+        return Ok(self.success_message(None));
+    }
+
+    // Lists the previous versions of a file kept by the server
+    fn list_versions(&self, file_name: String) -> io::Result<MessageSender> {
+        let file_path: PathBuf = PathBuf::from(&self.current_directory).join(&file_name);
+        if !self.is_valid_file(&file_path) {
+            return Ok(self.error_message(format_error(ERR_NO_PATH, &file_name)));
+        }
+        if !self.versions.is_enabled() {
+            return Ok(self.error_message(ERR_VERSIONS_DISABLED.to_string()));
+        }
+        let versions = self.versions.list(&self.storage.canonicalize(&file_path)?)?;
+        if versions.is_empty() {
+            return Ok(self.success_message(Some(format_error(MSG_NO_VERSIONS, &file_name))));
+        }
+        let output: String = versions
+            .iter()
+            .map(|version| format!("{}\t{}\t{}B", version.id, format_time(version.modified), version.size))
+            .collect::<Vec<String>>()
+            .join("\n");
+        return Ok(self.success_message(Some(output)));
+    }
+
+    // Replaces a file with one of its previous versions. The content being replaced becomes a version itself,
+    // so a restore can be undone.
+    fn restore(&self, arguments: String) -> io::Result<MessageSender> {
+        let (file_name, options) = split_options(&arguments);
+        let file_path: PathBuf = PathBuf::from(&self.current_directory).join(&file_name);
+        if !self.is_valid_file(&file_path) {
+            return Ok(self.error_message(format_error(ERR_NO_PATH, &file_name)));
+        }
+        let file_path = self.storage.canonicalize(&file_path)?;
+        if let Some(locked) = self.locked_by_other(&file_path)? {
+            return Ok(locked);
+        }
+        let version_id: Option<u32> = options.get("version").and_then(|version| version.parse().ok());
+        let version_path = match version_id {
+            Some(version_id) => self.versions.version_path(&file_path, version_id)?,
+            None => None,
+        };
+        let version_path = match version_path {
+            Some(version_path) => version_path,
+            None => {
+                let version = options.get("version").cloned().unwrap_or_default();
+                return Ok(self.error_message(format_error(ERR_NO_VERSION, &version)));
+            }
+        };
+
+        println!("ID {}: Restoring {:?} from {:?}", self.thread_id, file_path, version_path);
+        let mut version_file = self.storage.open_read(&version_path)?;
+        write_locked(&mut version_file, file_path.clone(), &self.fsrw_mutex, WriteOptions::default(), &self.versions, self.storage.as_ref())?;
+        self.ledger.refresh(&file_path)?;
+        return Ok(self.success_message(None));
+    }
+
+    // Moves a file or directory into the trash of the session's user instead of deleting it
+    fn rm(&self, path_name: String) -> io::Result<MessageSender> {
+        let path: PathBuf = PathBuf::from(&self.current_directory).join(&path_name);
+        if let Some(locked) = self.locked_by_other(&path)? {
+            return Ok(locked);
+        }
+        if self.is_valid_file(&path) {
+            let path = self.storage.canonicalize(&path)?;
+            self.trash.put(&self.user, &path, &self.fsrw_mutex)?;
+        } else if self.is_valid_directory(&path) {
+            let path = self.storage.canonicalize(&path)?;
+            if path == self.storage.canonicalize(&self.home_directory)? || self.current_directory.starts_with(&path) {
+                return Ok(self.error_message(format_error(ERR_REMOVE_HOME, &path_name)));
+            }
+            self.trash.put(&self.user, &path, &self.fsrw_mutex)?;
         } else {
-            file_message.write_to(&self.tcpstream, file_path, &self.fsrw_mutex)?;
+            return Ok(self.error_message(format_error(ERR_NO_PATH, &path_name)));
+        }
+        println!("ID {}: Moved {:?} to the trash of {}", self.thread_id, path, self.user);
+        return Ok(self.success_message(Some(format_error(MSG_TRASHED, &path_name))));
+    }
+
+    // Handles "trash ls", "trash restore" with the entry id as an option, and "trash empty"
+    fn trash(&self, arguments: String) -> io::Result<MessageSender> {
+        let (subcommand, options) = split_options(&arguments);
+        match subcommand.as_str() {
+            "ls" => {
+                let entries = self.trash.list(&self.user)?;
+                if entries.is_empty() {
+                    return Ok(self.success_message(Some(MSG_TRASH_EMPTY.to_string())));
+                }
+                let output: String = entries
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "{}\t{}\t~/{}",
+                            entry.id,
+                            format_time(entry.deleted),
+                            entry.original_path.to_string_lossy()
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                return Ok(self.success_message(Some(output)));
+            }
+            "restore" => {
+                let id_string: String = options.get("id").cloned().unwrap_or_default();
+                let entry = match id_string.parse::<u32>().ok().and_then(|id| self.trash.get(&self.user, id)) {
+                    Some(entry) => entry,
+                    None => return Ok(self.error_message(format_error(ERR_NO_TRASH_ENTRY, &id_string))),
+                };
+
+                // Never overwrites what has been created at the original location in the meantime. The destination
+                // is chosen while file_dict is locked, so that a session uploading to the same name can't take it
+                // in between, and is locked together with the entry being moved there.
+                let original_location = self.trash.original_location(&entry);
+                let source = [(self.trash.data_path(&self.user, entry.id), LockMode::Exclusive)];
+                let chosen = self.fsrw_mutex.write_chosen(&source, |taken| {
+                    match OverwritePolicy::Rename.resolve_with(&original_location, None, taken, &|_| None) {
+                        Resolution::Write(destination) => Ok(destination),
+                        _ => Err(()),
+                    }
+                })?;
+                let write_path = match chosen {
+                    Ok(write_path) => write_path,
+                    Err(()) => return Ok(self.error_message(format_error(ERR_NO_TRASH_ENTRY, &id_string))),
+                };
+                let destination: PathBuf = write_path.to_path_buf();
+                // The files counted towards the user's quota in the trash, and keep doing so where they are now
+                self.trash.restore(&self.user, entry.id, &destination)?;
+                drop(write_path);
+                let restored_name = match destination.strip_prefix(self.storage.canonicalize(&self.home_directory)?) {
+                    Ok(relative_path) => "~/".to_string() + &relative_path.to_string_lossy(),
+                    Err(_) => destination.to_string_lossy().to_string(),
+                };
+                return Ok(self.success_message(Some(format_error(MSG_RESTORED, &restored_name))));
+            }
+            "empty" => {
+                self.trash.empty(&self.user)?;
+                return Ok(self.success_message(None));
+            }
+            _ => return Ok(self.error_message(format_error(ERR_TRASH_COMMAND, &subcommand))),
+        }
+    }
+
+    // Describes the tree below a directory (paths, sizes, modification times and hashes) so the client can sync it
+    fn manifest(&self, dir_name: String) -> io::Result<MessageSender> {
+        let dir_path: PathBuf = PathBuf::from(&self.current_directory).join(&dir_name);
+        if !self.is_valid_directory(&dir_path) {
+            return Ok(self.error_message(format_error(ERR_NO_DIR, &dir_name)));
+        }
+        let dir_path = self.storage.canonicalize(&dir_path)?;
+
+        let mut manifest = Manifest::new();
+        for (relative_path, is_dir) in self.storage.list_tree(&dir_path, &is_hidden)? {
+            if is_dir {
+                manifest.insert(manifest_path(&relative_path), ManifestEntry::Directory);
+                continue;
+            }
+
+            // Read locked so that a file being uploaded is described either before or after the upload
+            let read_path = self.fsrw_mutex.read(dir_path.join(&relative_path))?;
+            let metadata = self.storage.stat(&read_path)?;
+            let entry = ManifestEntry::File {
+                size: metadata.size,
+                modified: metadata.modified,
+                hash: hash_reader(&mut self.storage.open_read(&read_path)?)?,
+            };
+            drop(read_path);
+            manifest.insert(manifest_path(&relative_path), entry);
+        }
+        return Ok(self.success_message(Some(encode_manifest(&manifest))));
+    }
+
+    // Sends an event for every change to the entries of a directory until the client sends an unwatch, which is
+    // answered like any other request. Changes are looked for whenever a session changed something, and
+    // regularly to notice changes made outside of the server.
+    fn watch(&mut self, dir_name: String) -> io::Result<MessageSender> {
+        let dir_path: PathBuf = PathBuf::from(&self.current_directory).join(&dir_name);
+        if !self.is_valid_directory(&dir_path) {
+            return Ok(self.error_message(format_error(ERR_NO_DIR, &dir_name)));
+        }
+        let dir_path = self.storage.canonicalize(&dir_path)?;
+
+        println!("ID {}: Watching {:?}", self.thread_id, dir_path);
+        let mut changes_seen: u64 = self.notifier.wait(0, Duration::ZERO);
+        let mut snapshot = DirectorySnapshot::take(self.storage.as_ref(), &dir_path);
+        self.success_message(None)
+            .send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref())?;
+        loop {
+            changes_seen = self.notifier.wait(changes_seen, WATCH_POLL_INTERVAL);
+            if self.request_pending()? {
+                break;
+            }
+            let new_snapshot = DirectorySnapshot::take(self.storage.as_ref(), &dir_path);
+            for event in snapshot.changes(&new_snapshot) {
+                MessageSender::new(MessageKind::Event, event.to_string(), None)
+                    .send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref())?;
+            }
+            snapshot = new_snapshot;
+        }
+
+        let unwatch_message = match self.receive_message() {
+            Some(message) => message,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed")),
         };
+        // Anything else ends the watch as well, but isn't handled as a request
+        if unwatch_message.command != MessageKind::Unwatch {
+            return self.unexpected_message(unwatch_message);
+        }
+        println!("ID {}: Stopped watching {:?}", self.thread_id, dir_path);
         return Ok(self.success_message(None));
     }
 
+    // Checks without blocking whether the client has sent something
+    fn request_pending(&self) -> io::Result<bool> {
+        self.tcpstream.set_nonblocking(true)?;
+        let mut byte: [u8; 1] = [0; 1];
+        let peek_result = self.tcpstream.peek(&mut byte);
+        self.tcpstream.set_nonblocking(false)?;
+        match peek_result {
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed")),
+            Ok(_) => return Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Switches the session to a user listed in the users file. The password is given as an option.
+    fn login(&mut self, arguments: String) -> io::Result<MessageSender> {
+        let (name, options) = split_options(&arguments);
+        if !self.users.is_enabled() {
+            return Ok(self.error_message(ERR_LOGIN_DISABLED.to_string()));
+        }
+        let password = options.get("password").cloned().unwrap_or_default();
+        match self.users.authenticate(&name, &password) {
+            Some(account) => {
+                self.user = account.name.clone();
+                if let Some(state) = &self.session_state {
+                    state.set_user(&self.user);
+                }
+                println!("ID {}: Logged in as {}", self.thread_id, self.user);
+                return Ok(self.success_message(Some(format_error(MSG_LOGGED_IN, &self.user))));
+            }
+            None => return Ok(self.error_message(format_error(ERR_LOGIN, &name))),
+        }
+    }
+
+    // Shows how much the user stores and how much they may store
+    fn quota(&self) -> io::Result<MessageSender> {
+        let report = QuotaReport {
+            usage: self.ledger.usage(&self.user),
+            quota: self.users.quota(&self.user),
+        };
+        return Ok(self.success_message(Some(format!("{}: {}", self.user, report))));
+    }
+
+    // What replacing the file at replaced frees of the user's usage, nothing unless it is one of their own files.
+    // Nothing is freed either when the replaced content is kept as a version.
+    fn freed_by(&self, replaced: Option<&Path>) -> io::Result<Usage> {
+        if self.versions.is_enabled() {
+            return Ok(Usage::default());
+        }
+        if let Some(replaced) = replaced.and_then(|replaced| self.storage.canonicalize(replaced).ok()) {
+            if self.ledger.owner(&replaced).as_deref() == Some(self.user.as_str()) {
+                return Ok(Usage {bytes: self.storage.stat(&replaced)?.size, files: 1});
+            }
+        }
+        return Ok(Usage::default());
+    }
+
+    // How many bytes an upload replacing the file at replaced may write before it takes the user over their quota
+    // or doesn't fit in the free space of the storage. None if neither limits it. An upload whose size isn't known
+    // takes all that is left of the quota in its reservation, one whose size is known only what it reserved.
+    fn upload_limit(&self, reservation: &mut Reservation, replaced: Option<&Path>, size_known: bool) -> io::Result<Option<u64>> {
+        let quota = self.users.quota(&self.user);
+        let mut limit: Option<u64> = match (quota.max_bytes, size_known) {
+            (None, _) => None,
+            (Some(_), true) => Some(reservation.bytes()),
+            (Some(_), false) => reservation.claim_room(self.freed_by(replaced)?, quota),
+        };
+        if let Some(space) = self.storage.space(&self.home_directory)? {
+            let available = space.available.saturating_sub(self.reserve);
+            limit = Some(limit.map_or(available, |limit| limit.min(available)));
+        }
+        return Ok(limit);
+    }
+
+    // The error reply for an upload that was stopped for getting larger than upload_limit allowed
+    fn too_large(&self, name: &str, too_large: &TooLarge) -> MessageSender {
+        println!("ID {}: {} got larger than the {} bytes it may take", self.thread_id, name, too_large.max_bytes);
+        return self.error_message(format!("{} ({} bytes allowed)", format_error(ERR_TOO_LARGE, name), too_large.max_bytes));
+    }
+
+    // Reserves an upload of bytes bytes in files new files in the user's quota, or an error reply if it doesn't fit
+    // in there or in the free space of the storage
+    fn reserve_upload(&self, name: &str, replaced: Option<&Path>, bytes: u64, files: u64) -> io::Result<Result<Reservation, MessageSender>> {
+        let quota = self.users.quota(&self.user);
+        let usage = Usage {bytes, files};
+        let reservation = match self.ledger.reserve(&self.user, usage, self.freed_by(replaced)?, quota) {
+            Ok(reservation) => reservation,
+            Err(in_use) => return Ok(Err(self.over_quota(name, in_use))),
+        };
+        if let Some(out_of_space) = self.out_of_space(name, bytes)? {
+            return Ok(Err(out_of_space));
+        }
+        return Ok(Ok(reservation));
+    }
+
+    // Grows or shrinks a reservation to the actual size of the upload, or an error reply if that doesn't fit
+    fn resize_upload(&self, reservation: &mut Reservation, name: &str, replaced: Option<&Path>, bytes: u64, files: u64) -> io::Result<Option<MessageSender>> {
+        let quota = self.users.quota(&self.user);
+        if let Err(in_use) = reservation.resize(Usage {bytes, files}, self.freed_by(replaced)?, quota) {
+            return Ok(Some(self.over_quota(name, in_use)));
+        }
+        return self.out_of_space(name, bytes);
+    }
+
+    // The new content is written next to the file it replaces, so the whole size has to fit even when replacing
+    fn out_of_space(&self, name: &str, bytes: u64) -> io::Result<Option<MessageSender>> {
+        let space = match self.storage.space(&self.home_directory)? {
+            Some(space) => space,
+            None => return Ok(None),
+        };
+        let available = space.available.saturating_sub(self.reserve);
+        if bytes <= available {
+            return Ok(None);
+        }
+        println!("ID {}: {} bytes don't fit in {} bytes available", self.thread_id, bytes, available);
+        return Ok(Some(self.error_message(format!("{} ({} bytes available)", format_error(ERR_NO_SPACE, name), available))));
+    }
+
+    // Reports the space of the storage holding the shared files
+    fn df(&self) -> io::Result<MessageSender> {
+        let space = match self.storage.space(&self.home_directory)? {
+            Some(space) => space,
+            None => return Ok(self.error_message(ERR_SPACE_UNKNOWN.to_string())),
+        };
+        let output = format!(
+            "Total\t{}B\nUsed\t{}B\nFree\t{}B\nReserved\t{}B",
+            space.total,
+            space.used,
+            space.available.saturating_sub(self.reserve),
+            self.reserve.min(space.available)
+        );
+        return Ok(self.success_message(Some(output)));
+    }
+
+    // The error reply for an upload that doesn't fit in the user's quota, with in_use what they store and what the
+    // uploads of their other sessions have reserved
+    fn over_quota(&self, name: &str, in_use: Usage) -> MessageSender {
+        let report = QuotaReport { usage: in_use, quota: self.users.quota(&self.user) };
+        return self.error_message(format!("{} ({} used)", format_error(ERR_QUOTA, name), report));
+    }
+
+    // Takes an advisory lock on a file, or extends the one the session holds. The duration in minutes can be
+    // given as an option.
+    fn lock(&self, arguments: String) -> io::Result<MessageSender> {
+        let (file_name, options) = split_options(&arguments);
+        let file_path: PathBuf = PathBuf::from(&self.current_directory).join(&file_name);
+        if !self.is_valid_file(&file_path) {
+            return Ok(self.error_message(format_error(ERR_NO_PATH, &file_name)));
+        }
+        let file_path = self.storage.canonicalize(&file_path)?;
+        let duration: Duration = match options.get("minutes") {
+            Some(minutes) => match minutes.parse::<u64>().ok().and_then(|minutes| minutes.checked_mul(60)) {
+                Some(seconds) if seconds > 0 && Duration::from_secs(seconds) <= MAX_LOCK_DURATION => {
+                    Duration::from_secs(seconds)
+                }
+                _ => return Ok(self.error_message(format_error(ERR_LOCK_DURATION, minutes))),
+            },
+            None => DEFAULT_LOCK_DURATION,
+        };
+        match self.locks.lock(&file_path, &self.lock_owner(), duration) {
+            Ok(lock) => {
+                println!("ID {}: {:?} {}", self.thread_id, file_path, lock);
+                let message = format!("{} until {}", format_error(MSG_LOCKED, &file_name), format_time(lock.expires));
+                return Ok(self.success_message(Some(message)));
+            }
+            Err(lock) => return Ok(self.error_message(format!("{}: {}", format_error(ERR_LOCKED, &file_name), lock))),
+        }
+    }
+
+    fn unlock(&self, file_name: String) -> io::Result<MessageSender> {
+        let file_path: PathBuf = PathBuf::from(&self.current_directory).join(&file_name);
+        if !self.is_valid_file(&file_path) {
+            return Ok(self.error_message(format_error(ERR_NO_PATH, &file_name)));
+        }
+        let file_path = self.storage.canonicalize(&file_path)?;
+        match self.locks.unlock(&file_path, &self.lock_owner()) {
+            Ok(()) => return Ok(self.success_message(Some(format_error(MSG_UNLOCKED, &file_name)))),
+            Err(Some(lock)) => {
+                return Ok(self.error_message(format!("{}: {}", format_error(ERR_LOCKED, &file_name), lock)))
+            }
+            Err(None) => return Ok(self.error_message(format_error(ERR_NOT_LOCKED, &file_name))),
+        }
+    }
+
+    // An error reply if path, or anything in it, is locked by someone else. path doesn't have to exist.
+    fn locked_by_other(&self, path: &Path) -> io::Result<Option<MessageSender>> {
+        let path = match self.storage.canonicalize(path) {
+            Ok(path) => path,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match self.locks.blocking(&path, &self.lock_owner()) {
+            Some(lock) => {
+                let name = self.get_display_path(&path);
+                let name = name.trim_end_matches('/');
+                return Ok(Some(self.error_message(format!("{}: {}", format_error(ERR_LOCKED, name), lock))));
+            }
+            None => return Ok(None),
+        }
+    }
+
+    // Lists who holds and waits for the files being accessed, one "path\tmode\tholder" line per holder
+    fn list_locks(&self) -> io::Result<MessageSender> {
+        if !self.is_admin() {
+            return Ok(self.error_message(format_error(ERR_NOT_ADMIN, "locks")));
+        }
+        // Locked paths are canonical
+        let home_directory = self.storage.canonicalize(&self.home_directory)?;
+        let mut lines: Vec<String> = Vec::new();
+        for holdings in self.fsrw_mutex.holdings() {
+            // Paths outside the home folder and the hidden ones of the server aren't shown
+            if !holdings.path.starts_with(&home_directory) || self.is_server_path(&holdings.path) {
+                continue;
+            }
+            let mut display_path = self.get_display_path(&holdings.path);
+            if !self.storage.is_dir(&holdings.path) {
+                display_path = display_path.trim_end_matches('/').to_string();
+            }
+            for holder in holdings.holders {
+                let session = match holder.session {
+                    Some(session) => format!("session {} ({})", session.id, session.user()),
+                    None => "server".to_string(),
+                };
+                lines.push(format!("{}\t{}\t{} since {}", display_path, holder.mode.describe(), session, format_time(holder.since)));
+            }
+            if holdings.waiting > 0 {
+                lines.push(format!("{}\twaiting\t{} sessions", display_path, holdings.waiting));
+            }
+        }
+        if lines.is_empty() {
+            return Ok(self.success_message(Some(MSG_NO_LOCKS.to_string())));
+        }
+        return Ok(self.success_message(Some(lines.join("\n"))));
+    }
+
+    // Lists the connected clients, one "id\taddress\tuser\tdirectory\ttransfer" line per session
+    fn list_sessions(&self) -> io::Result<MessageSender> {
+        if !self.is_admin() {
+            return Ok(self.error_message(format_error(ERR_NOT_ADMIN, "sessions")));
+        }
+        let lines: Vec<String> = self
+            .sessions
+            .list()
+            .iter()
+            .map(|session| {
+                let details = session.details();
+                let transfer = match details.transfer {
+                    Some(transfer) => {
+                        let transferred = session.transferred();
+                        match transfer.size {
+                            Some(size) if size > 0 => format!(
+                                "{} {}: {} of {} bytes ({}%)",
+                                transfer.direction,
+                                transfer.name,
+                                transferred,
+                                size,
+                                transferred.min(size) * 100 / size
+                            ),
+                            _ => format!("{} {}: {} bytes", transfer.direction, transfer.name, transferred),
+                        }
+                    }
+                    None => "idle".to_string(),
+                };
+                format!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    session.id,
+                    session.address,
+                    details.user,
+                    self.get_display_path(&details.directory),
+                    transfer
+                )
+            })
+            .collect();
+        return Ok(self.success_message(Some(lines.join("\n"))));
+    }
+
+    // Anonymous sessions are never administrators, even when logging in is disabled
+    fn is_admin(&self) -> bool {
+        return self.user != ANONYMOUS_USER && self.admins.contains(&self.user);
+    }
+
+    fn start_transfer(&self, direction: &'static str, name: &str, size: Option<u64>) {
+        if let Some(state) = &self.session_state {
+            state.start_transfer(direction, name, size);
+        }
+    }
+
+    // None for a chunked payload, whose size isn't known until it has been received
+    fn payload_size(message: &MessageReceiver) -> Option<u64> {
+        if message.payload_size == CHUNKED_PAYLOAD {
+            return None;
+        }
+        return Some(message.payload_size);
+    }
+
+    fn lock_owner(&self) -> LockOwner {
+        return LockOwner {
+            user: self.user.clone(),
+            session: self.session,
+        };
+    }
+
+    // Skips the payload of a message the client wasn't supposed to send at this point, so that the next one can be
+    // read, and replies with an error
+    fn unexpected_message(&self, message: MessageReceiver) -> io::Result<MessageSender> {
+        println!("ID {}: Unexpected {:?} message", self.thread_id, message.command);
+        let kind = format!("{:?}", message.command);
+        message.discard(&self.tcpstream)?;
+        return Ok(self.error_message(format_error(ERR_UNEXPECTED_MESSAGE, &kind)));
+    }
+
     // Creates a MessageSender of MessageKind::Success
     fn success_message(&self, message_string: Option<String>) -> MessageSender {
         let message_string = match message_string {
@@ -312,10 +1220,13 @@ impl ConnectionHandler {
 
     // Checks if new path is within the sandboxed folder
     fn is_valid_directory(&self, path: &PathBuf) -> bool {
-        if path.exists() && path.is_dir() {
+        if self.storage.is_dir(path) {
             // JANK WAY TO CHECK: Merely checks if the home folder name is within the new path. Can obviously be bypassed if there are other folders with the same name as the home folder.
-            let simplified_path = path.canonicalize().unwrap();
+            let simplified_path = self.storage.canonicalize(path).unwrap();
             // println!("ID {}: Checking valid dir: {:?}",self.thread_id,&simplified_path);
+            if self.is_server_path(&simplified_path) {
+                return false;
+            }
             let home_folder_name = &self.home_directory.iter().last().unwrap();
             let path_from_current_directory: PathBuf = simplified_path
                 .clone()
@@ -329,11 +1240,36 @@ impl ConnectionHandler {
         return false;
     }
 
+    // Checks if the last component of a path that may not exist yet is reserved for the server
+    fn is_hidden_name(&self, path: &Path) -> bool {
+        match path.file_name() {
+            Some(name) => is_hidden(&name.to_string_lossy()),
+            None => false,
+        }
+    }
+
+    // Checks if a canonicalized path is one of the server's own files, e.g. the versions area, which clients can't access
+    fn is_server_path(&self, simplified_path: &Path) -> bool {
+        let home_directory = match self.storage.canonicalize(&self.home_directory) {
+            Ok(home_directory) => home_directory,
+            Err(_) => return false,
+        };
+        match simplified_path.strip_prefix(home_directory) {
+            Ok(relative_path) => relative_path
+                .iter()
+                .any(|component| is_hidden(&component.to_string_lossy())),
+            Err(_) => false,
+        }
+    }
+
     // Checks if file is within the sandboxed folder
     fn is_valid_file(&self, path: &PathBuf) -> bool {
-        if path.exists() && path.is_file() {
+        if self.storage.is_file(path) {
             // JANK WAY TO CHECK: Merely checks if the home folder name is within the new path. Can obviously be bypassed if there are other folders with the same name as the home folder.
-            let simplified_path = path.canonicalize().unwrap();
+            let simplified_path = self.storage.canonicalize(path).unwrap();
+            if self.is_server_path(&simplified_path) {
+                return false;
+            }
             // println!(
             //     "ID {}: Checking valid file: {:?}",
             //     self.thread_id, &simplified_path
@@ -359,7 +1295,7 @@ impl ConnectionHandler {
 //     // TODO: make this send it to all clients
 //     fn drop(&mut self) {
 //         let error_message: MessageSender = self.error_message("You have been disconnected.".to_string());
-//         let final_msg_result = error_message.send_message(&self.tcpstream, &self.fsrw_mutex);
+//         let final_msg_result = error_message.send_message(&self.tcpstream, &self.fsrw_mutex, self.storage.as_ref());
 //         if let Err(e) = final_msg_result {
 //             println!("ID {}: {}",self.thread_id, e);
 //         }
@@ -367,3 +1303,131 @@ impl ConnectionHandler {
 //         self.tcpstream.shutdown(std::net::Shutdown::Both);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::thread;
+
+    use crate::client::message::receiver::MessageReceiver as ClientReceiver;
+    use crate::client::message::sender::MessageSender as ClientSender;
+    use crate::delta::{decode_signatures, write_delta};
+    use crate::manifest::decode_manifest;
+    use crate::message::add_option;
+    use crate::server::storage::MemoryStorage;
+
+    // Starts a session of a handler sharing /home of storage and returns the client's end of it
+    fn connect(storage: Arc<dyn StorageBackend>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let client = TcpStream::connect(&addr).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let config = ServerConfig { max_versions: 2, ..ServerConfig::default() };
+        let ledger = Arc::new(UsageLedger::load(Path::new("/home"), Arc::clone(&storage)).unwrap());
+        let handler = ConnectionHandler::new(
+            stream,
+            PathBuf::from("/home"),
+            Arc::new(FsrwMutex::new(Arc::clone(&storage))),
+            addr,
+            Arc::new(config),
+            storage,
+            Arc::new(ChangeNotifier::new()),
+            Arc::new(AdvisoryLocks::new()),
+            Arc::new(Users::none()),
+            ledger,
+            Arc::new(Sessions::new()),
+        )
+        .unwrap();
+        thread::spawn(move || handler.handle_connection());
+
+        let mut port = [0u8; 4];
+        (&client).read_exact(&mut port).unwrap();
+        let session = TcpStream::connect(("127.0.0.1", i32::from_le_bytes(port) as u16)).unwrap();
+        assert_eq!(ClientReceiver::new(&session).unwrap().command, MessageKind::Success);
+        return session;
+    }
+
+    fn request(stream: &TcpStream, command: MessageKind, arguments: &str) -> ClientReceiver {
+        ClientSender::new(command, arguments.to_string(), None).send_message(stream).unwrap();
+        return ClientReceiver::new(stream).unwrap();
+    }
+
+    fn upload(stream: &TcpStream, file_name: &str, content: &[u8]) {
+        let ready = request(stream, MessageKind::Up, file_name);
+        assert_eq!((ready.command, ready.arguments.as_str()), (MessageKind::Success, ""));
+        let file_message = ClientSender::new(MessageKind::File, "".to_string(), None);
+        file_message.send_chunked(stream, |writer| writer.write_all(content)).unwrap().unwrap();
+        let done = ClientReceiver::new(stream).unwrap();
+        assert_eq!(done.command, MessageKind::Success, "{}", done.arguments);
+    }
+
+    // Nothing the handler does may need the local filesystem, so a whole session runs on storage kept in memory
+    #[test]
+    fn session_on_memory_storage() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.create_dir(Path::new("/home")).unwrap();
+        let stream = connect(Arc::clone(&storage));
+        let notes = Path::new("/home/docs/notes.txt");
+
+        assert_eq!(request(&stream, MessageKind::Mkdir, "docs").command, MessageKind::Success);
+        upload(&stream, "docs/notes.txt", b"first");
+        upload(&stream, "docs/notes.txt", b"second");
+        assert_eq!(storage.read_to_string(notes).unwrap(), "second");
+        let versions = request(&stream, MessageKind::Versions, "docs/notes.txt");
+        assert!(versions.arguments.starts_with("1\t") && versions.arguments.ends_with("\t5B"), "{}", versions.arguments);
+
+        let download = request(&stream, MessageKind::Down, "docs/notes.txt");
+        assert_eq!(download.command, MessageKind::File);
+        assert_eq!(download.read_payload(&stream).unwrap(), b"second");
+
+        // The hidden folder holding the versions is left out
+        let manifest = request(&stream, MessageKind::Manifest, ".");
+        let manifest = decode_manifest(&manifest.arguments).unwrap();
+        assert_eq!(manifest.keys().collect::<Vec<&String>>(), vec!["docs", "docs/notes.txt"]);
+        match &manifest["docs/notes.txt"] {
+            ManifestEntry::File { size, hash, .. } => {
+                assert_eq!(*size, 6);
+                assert_eq!(*hash, hash_reader(&mut &b"second"[..]).unwrap());
+            }
+            entry => panic!("{:?} is not a file", entry),
+        }
+
+        assert_eq!(request(&stream, MessageKind::Rm, "docs").command, MessageKind::Success);
+        assert!(!storage.exists(notes));
+        let trashed = request(&stream, MessageKind::Trash, "ls");
+        assert!(trashed.arguments.starts_with("1\t") && trashed.arguments.ends_with("~/docs"), "{}", trashed.arguments);
+        let mut arguments = "restore".to_string();
+        add_option(&mut arguments, "id", "1");
+        assert_eq!(request(&stream, MessageKind::Trash, &arguments).command, MessageKind::Success);
+        assert_eq!(storage.read_to_string(notes).unwrap(), "second");
+    }
+
+    // A delta describes the file as it was when its signatures were sent, so it isn't applied to anything else
+    #[test]
+    fn delta_over_a_changed_basis_is_refused() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.create_dir(Path::new("/home")).unwrap();
+        let stream = connect(Arc::clone(&storage));
+        let basis: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        upload(&stream, "a", &basis);
+
+        let mut arguments = "a".to_string();
+        add_option(&mut arguments, "delta", "1");
+        let signatures = request(&stream, MessageKind::Up, &arguments);
+        assert_eq!(signatures.command, MessageKind::Signature);
+        let (block_size, signatures) = decode_signatures(&signatures.read_payload(&stream).unwrap()).unwrap();
+        storage.write(Path::new("/home/a"), b"changed by someone else").unwrap();
+
+        let target = [&basis[..], b"appended"].concat();
+        let delta_message = ClientSender::new(MessageKind::Delta, "".to_string(), None);
+        delta_message
+            .send_chunked(&stream, |writer| write_delta(target.as_slice(), block_size, &signatures, writer).map(|_| ()))
+            .unwrap()
+            .unwrap();
+        let refused = ClientReceiver::new(&stream).unwrap();
+        assert_eq!(refused.command, MessageKind::Error);
+        assert_eq!(refused.arguments, format_error(ERR_BASIS_CHANGED, "a"));
+        assert_eq!(storage.read_to_string(Path::new("/home/a")).unwrap(), "changed by someone else");
+    }
+}
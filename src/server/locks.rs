@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use crate::server::utilities::ANONYMOUS_USER;
+use crate::utilities::format_time;
+
+// Who holds an advisory lock. Logged in users hold their locks in all of their sessions, anonymous locks belong
+// to the session that took them.
+#[derive(Debug, Clone)]
+pub struct LockOwner {
+    pub user: String,
+    pub session: u64,
+}
+
+impl LockOwner {
+    fn is(&self, other: &LockOwner) -> bool {
+        if self.user == ANONYMOUS_USER || other.user == ANONYMOUS_USER {
+            return self.session == other.session;
+        }
+        return self.user == other.user;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AdvisoryLock {
+    pub owner: LockOwner,
+    pub since: SystemTime,
+    pub expires: SystemTime,
+}
+
+// e.g. "locked by alice since 10:42 UTC"
+impl fmt::Display for AdvisoryLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only the time of day, the date is part of the full format
+        let since = format_time(self.since);
+        write!(f, "locked by {} since {}", self.owner.user, &since[11..])
+    }
+}
+
+// Locks that clients take on files for longer than a transfer, e.g. while they edit them. They don't keep anyone
+// from reading the file, but uploads and other changes by anyone but the owner are refused until the lock is
+// released or expires.
+pub struct AdvisoryLocks {
+    locks: Mutex<HashMap<PathBuf, AdvisoryLock>>,
+    sessions: AtomicU64,
+}
+
+impl AdvisoryLocks {
+    pub fn new() -> Self {
+        Self {
+            locks: Mutex::new(HashMap::new()),
+            sessions: AtomicU64::new(0),
+        }
+    }
+
+    // Identifies a session for the locks it takes
+    pub fn new_session(&self) -> u64 {
+        return self.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+    }
+
+    // Locks file_path, a canonicalized path, for duration, or extends the lock if owner holds it already.
+    // Fails with the current lock if someone else holds it.
+    pub fn lock(&self, file_path: &Path, owner: &LockOwner, duration: Duration) -> Result<AdvisoryLock, AdvisoryLock> {
+        let mut locks = self.locked();
+        let now = SystemTime::now();
+        let since = match locks.get(file_path) {
+            Some(lock) if !lock.owner.is(owner) => return Err(lock.clone()),
+            Some(lock) => lock.since,
+            None => now,
+        };
+        let lock = AdvisoryLock {
+            owner: owner.clone(),
+            since,
+            expires: now + duration,
+        };
+        locks.insert(file_path.to_path_buf(), lock.clone());
+        return Ok(lock);
+    }
+
+    // Releases the lock of owner on file_path. Fails with the lock if someone else holds it, or with None if the
+    // file isn't locked.
+    pub fn unlock(&self, file_path: &Path, owner: &LockOwner) -> Result<(), Option<AdvisoryLock>> {
+        let mut locks = self.locked();
+        match locks.get(file_path) {
+            Some(lock) if lock.owner.is(owner) => {
+                locks.remove(file_path);
+                return Ok(());
+            }
+            Some(lock) => return Err(Some(lock.clone())),
+            None => return Err(None),
+        }
+    }
+
+    pub fn get(&self, file_path: &Path) -> Option<AdvisoryLock> {
+        return self.locked().get(file_path).cloned();
+    }
+
+    // A lock that keeps owner from changing path, a canonicalized file or directory, if there is any
+    pub fn blocking(&self, path: &Path, owner: &LockOwner) -> Option<AdvisoryLock> {
+        let locks = self.locked();
+        return locks
+            .iter()
+            .find(|(locked_path, lock)| locked_path.starts_with(path) && !lock.owner.is(owner))
+            .map(|(_, lock)| lock.clone());
+    }
+
+    // The locks of an anonymous session can't be released by anyone else, so they end with it
+    pub fn end_session(&self, session: u64) {
+        let mut locks = self.locked();
+        locks.retain(|_, lock| !(lock.owner.user == ANONYMOUS_USER && lock.owner.session == session));
+    }
+
+    // Drops expired locks before handing out the locks
+    fn locked(&self) -> MutexGuard<'_, HashMap<PathBuf, AdvisoryLock>> {
+        let mut locks = match self.locks.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                println!("Recovering the advisory locks after a thread panicked");
+                self.locks.clear_poison();
+                poisoned.into_inner()
+            }
+        };
+        let now = SystemTime::now();
+        locks.retain(|_, lock| lock.expires > now);
+        return locks;
+    }
+}
+
+impl Default for AdvisoryLocks {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
@@ -1,13 +1,62 @@
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::error;
+use std::fmt;
+use std::io;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
-use std::sync::RwLockWriteGuard;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::io::BufReader;
 
-use crate::message::{MessageKind, BUFFER_SIZE, HEADER_SIZE};
+use crate::delta::{apply_delta, block_size_for, compute_signatures, encode_signatures, BlockSignature};
+use crate::message::{split_options, ChunkedReader, FileAttributes, MessageKind, SizedReader, BUFFER_SIZE, CHUNKED_PAYLOAD, HEADER_SIZE};
+use crate::policy::{is_newer_than, OverwritePolicy, Resolution};
 use crate::server::fsrw_mutex::*;
+use crate::server::sessions::CountingReader;
+use crate::server::storage::{StorageBackend, StorageMetadata};
+use crate::server::versions::VersionStore;
+use crate::server::utilities::is_safe_relative_path;
+
+// How an upload is written: the policy for an existing destination, the modification time of the client's file
+// that OverwritePolicy::Newer compares, the attributes the file gets, and how large it may get
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    pub policy: OverwritePolicy,
+    pub source_modified: Option<SystemTime>,
+    pub attributes: FileAttributes,
+    // Writing fails with TooLarge once the file gets larger than this. None for no limit.
+    pub max_bytes: Option<u64>,
+}
+
+// How an archive is unpacked: entries for which locked returns true are rejected, the files together may take up at
+// most max_bytes, and policy decides what happens to entries whose destination exists, compared by the modification
+// times in the archive
+pub struct ExtractOptions<'a> {
+    pub locked: &'a dyn Fn(&Path) -> io::Result<bool>,
+    pub max_bytes: Option<u64>,
+    pub policy: OverwritePolicy,
+}
+
+// The error of a write that was stopped because the file got larger than the max_bytes of its WriteOptions
+#[derive(Debug)]
+pub struct TooLarge {
+    pub max_bytes: u64,
+}
+
+impl TooLarge {
+    // The TooLarge that caused e, if any
+    pub fn of(e: &io::Error) -> Option<&TooLarge> {
+        return e.get_ref()?.downcast_ref::<TooLarge>();
+    }
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The file is larger than the {} bytes it may take", self.max_bytes)
+    }
+}
+
+impl error::Error for TooLarge {}
 
 #[derive(Debug)]
 pub struct MessageReceiver {
@@ -34,7 +83,10 @@ impl MessageReceiver {
         let mut argument_bytes: Vec<u8> = vec![0u8; argument_size as usize];
         tcpstream.read_exact(&mut argument_bytes)?;
         let argument_string = from_utf8(&argument_bytes).unwrap().to_string();
-        payload_size -= HEADER_SIZE as u64 + argument_size as u64;
+        // Chunked payloads keep the marker as their size as the real size is unknown
+        if payload_size != CHUNKED_PAYLOAD {
+            payload_size -= HEADER_SIZE as u64 + argument_size as u64;
+        }
 
         // println!("message size: {}", payload_size);
         // println!("command: {}", headers[8]);
@@ -52,89 +104,376 @@ impl MessageReceiver {
         Ok(message_receiver)
     }
 
-    // Writes the message payload to a file_path. Assumes that file_path is valid!
+    // Returns a reader over the payload of this message, whether its size was announced or it is chunked.
+    // What is read counts towards the progress of the session's transfer.
+    pub fn payload_reader<'a>(&self, tcpstream: &'a TcpStream) -> Box<dyn Read + 'a> {
+        match self.payload_size {
+            CHUNKED_PAYLOAD => Box::new(CountingReader::new(ChunkedReader::new(tcpstream))),
+            _ => Box::new(CountingReader::new(SizedReader::new(tcpstream, self.payload_size))),
+        }
+    }
+
+    // Attributes the sender wants the file to keep, which are only sent when they are to be preserved
+    pub fn attributes(&self) -> FileAttributes {
+        let (_, options) = split_options(&self.arguments);
+        return FileAttributes::from_options(&options).with_owner_access();
+    }
+
+    // Reads and throws away the payload, so that the next message can be read
+    pub fn discard(self, tcpstream: &TcpStream) -> io::Result<()> {
+        io::copy(&mut self.payload_reader(tcpstream), &mut io::sink())?;
+        return Ok(());
+    }
+
+    // Writes the message payload to a file_path as options say. Assumes that file_path is valid!
+    // When the policy keeps the payload from being written, or the file can't be locked, it is read and discarded.
     pub fn write_to(
         self,
         tcpstream: &TcpStream,
         file_path: PathBuf,
         fsrw_mutex: &FsrwMutex,
-    ) -> io::Result<()> {
+        options: WriteOptions,
+        versions: &VersionStore,
+        storage: &dyn StorageBackend,
+    ) -> io::Result<Resolution> {
+        let mut reader = self.payload_reader(tcpstream);
+        let write_result = write_locked(&mut reader, file_path, fsrw_mutex, options, versions, storage);
+        if let Ok(Resolution::Skip | Resolution::Fail) | Err(_) = write_result {
+            io::copy(&mut reader, &mut io::sink())?;
+        }
+        return write_result;
+    }
+
+    // Rebuilds the file of basis from the delta in the payload and the blocks of the copy described by basis. The
+    // file is only replaced if it is still that copy once the write lock is taken, otherwise None is returned.
+    pub fn apply_delta_to(
+        self,
+        tcpstream: &TcpStream,
+        basis: &DeltaBasis,
+        fsrw_mutex: &FsrwMutex,
+        options: WriteOptions,
+        versions: &VersionStore,
+        storage: &dyn StorageBackend,
+    ) -> io::Result<Option<Resolution>> {
+        let mut reader = self.payload_reader(tcpstream);
+        let mut basis_changed: bool = false;
+        let write_result = write_locked_with(basis.path.clone(), fsrw_mutex, options, versions, storage, &mut |locked_path, mut writer| {
+            if !basis.matches(&storage.stat(locked_path)?) {
+                basis_changed = true;
+                return Err(io::Error::new(io::ErrorKind::Other, "File changed since its signatures were sent"));
+            }
+            let mut basis_file = storage.open_read(locked_path)?;
+            return apply_delta(&mut reader, &mut basis_file, basis.block_size, &mut writer);
+        });
+
+        // The rest of the payload has to be read even if the delta wasn't applied, so that the next message can be read
+        io::copy(&mut reader, &mut io::sink())?;
+        if basis_changed {
+            return Ok(None);
+        }
+        return write_result.map(Some);
+    }
+
+    // Unpacks the payload, a tar archive, into dir_path while it is being received. Each file is committed
+    // atomically under its own write lock, just like a normal upload. Entries that would land outside of
+    // dir_path, that are not plain files or directories, whose file is busy or locked by someone else, or that don't
+    // fit in what is left of the max_bytes of options, are skipped, and so are those whose destination exists and
+    // that the policy of options refuses to write. Files renamed by the policy are written next to their destination.
+    // Returns the files that were written and the entries that were skipped.
+    pub fn extract_to(
+        self,
+        tcpstream: &TcpStream,
+        dir_path: PathBuf,
+        fsrw_mutex: &FsrwMutex,
+        options: ExtractOptions,
+        versions: &VersionStore,
+        storage: &dyn StorageBackend,
+    ) -> io::Result<(Vec<PathBuf>, Vec<String>)> {
+        let mut reader = self.payload_reader(tcpstream);
+        let mut extracted = Extracted::default();
+        let extract_result = extract_archive(&mut reader, &dir_path, fsrw_mutex, &options, versions, storage, &mut extracted);
+
+        // The rest of the payload has to be read even if the archive was invalid, so that the next message can be read
+        io::copy(&mut reader, &mut io::sink())?;
+        extract_result?;
+        return Ok((extracted.written, extracted.rejected));
+    }
+}
+
+// The copy of a file that a delta upload is based on, as it was when its signatures were computed
+#[derive(Debug)]
+pub struct DeltaBasis {
+    // The file as the client named it
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    pub block_size: usize,
+    pub signatures: Vec<BlockSignature>,
+}
+
+impl DeltaBasis {
+    // Computes the signatures of file_path under its read lock
+    pub fn read(file_path: PathBuf, fsrw_mutex: &FsrwMutex, storage: &dyn StorageBackend) -> io::Result<Self> {
+        let read_path = fsrw_mutex.read(file_path.clone())?;
+        return Self::critical_region_read(file_path, read_path.as_path(), storage);
+    }
 
+    fn critical_region_read(path: PathBuf, read_path: &Path, storage: &dyn StorageBackend) -> io::Result<Self> {
+        let metadata = storage.stat(read_path)?;
+        let block_size: usize = block_size_for(metadata.size);
+        let reader = BufReader::with_capacity(BUFFER_SIZE, storage.open_read(read_path)?);
+        let signatures = compute_signatures(reader, block_size)?;
+        return Ok(Self {
+            path,
+            size: metadata.size,
+            modified: metadata.modified,
+            block_size,
+            signatures,
+        });
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        return encode_signatures(self.block_size, &self.signatures);
+    }
+
+    fn matches(&self, metadata: &StorageMetadata) -> bool {
+        return metadata.size == self.size && metadata.modified == self.modified;
+    }
+}
 
-        // Acquire write access to the file
-        // Lock file_dict
-        let file_dict = match fsrw_mutex.file_dict.lock() {
-            Ok(guard) => guard,
-            // Need to handle this properly
-            Err(poisoned) => {
-                panic!("file_dict poisoned: {}", poisoned)
+// What extract_archive got to before it finished or failed
+#[derive(Default)]
+struct Extracted {
+    written: Vec<PathBuf>,
+    rejected: Vec<String>,
+    // Size of the written files together
+    bytes: u64,
+}
+
+fn extract_archive<R: Read>(
+    reader: &mut R,
+    dir_path: &Path,
+    fsrw_mutex: &FsrwMutex,
+    options: &ExtractOptions,
+    versions: &VersionStore,
+    storage: &dyn StorageBackend,
+    extracted: &mut Extracted,
+) -> io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path: PathBuf = entry.path()?.to_path_buf();
+        let entry_name: String = entry_path.to_string_lossy().to_string();
+        if !is_safe_relative_path(&entry_path) {
+            println!("Rejected archive entry {}", entry_name);
+            extracted.rejected.push(entry_name);
+            continue;
+        }
+
+        let target_path = dir_path.join(&entry_path);
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            storage.create_dir_all(&target_path)?;
+        } else if entry_type.is_file() {
+            if (options.locked)(&target_path)? {
+                println!("Rejected archive entry {}: it is locked", entry_name);
+                extracted.rejected.push(entry_name);
+                continue;
             }
-        };
-        let file_lock = acquire_file_rwlock(file_dict, file_path.to_path_buf());
-        // file_dict is automatically unlocked when acquire_file_rwlock consumes it
-
-        // Lock rwlock as a writer
-        let write_path = match file_lock.write() {
-            Ok(guard) => guard,
-            // Need to handle this properly
-            Err(poisoned) => {
-                panic!("file_dict poisoned: {}", poisoned)
+            storage.create_dir_all(target_path.parent().unwrap())?;
+            let source_modified = entry.header().mtime().ok().and_then(|mtime| UNIX_EPOCH.checked_add(Duration::from_secs(mtime)));
+            let write_options = WriteOptions {
+                policy: options.policy,
+                source_modified,
+                max_bytes: options.max_bytes.map(|max_bytes| max_bytes.saturating_sub(extracted.bytes)),
+                ..WriteOptions::default()
+            };
+            let write_result = write_locked(&mut entry, target_path.clone(), fsrw_mutex, write_options, versions, storage);
+            match write_result {
+                Ok(Resolution::Write(write_path)) => {
+                    extracted.bytes += storage.stat(&write_path)?.size;
+                    extracted.written.push(write_path);
+                }
+                Ok(Resolution::Skip) => println!("Skipped archive entry {}", entry_name),
+                Ok(Resolution::Fail) => {
+                    println!("Rejected archive entry {}: it exists", entry_name);
+                    extracted.rejected.push(entry_name);
+                }
+                Err(e) if FileBusy::of(&e).is_some() || TooLarge::of(&e).is_some() => {
+                    println!("Rejected archive entry {}: {}", entry_name, e);
+                    extracted.rejected.push(entry_name);
+                }
+                Err(e) => return Err(e),
             }
-        };
+        } else {
+            println!("Rejected archive entry {}", entry_name);
+            extracted.rejected.push(entry_name);
+        }
+    }
+    return Ok(());
+}
 
-        // Write here
-        let write_result = critical_region_write(self.payload_size, write_path, &tcpstream);
+// Takes the write lock on the destination chosen by the policy of options and writes everything from reader to it.
+// The content being replaced is kept in versions. The file gets those of the attributes of options that are set.
+pub fn write_locked<R: Read>(
+    reader: &mut R,
+    file_path: PathBuf,
+    fsrw_mutex: &FsrwMutex,
+    options: WriteOptions,
+    versions: &VersionStore,
+    storage: &dyn StorageBackend,
+) -> io::Result<Resolution> {
+    return write_locked_with(file_path, fsrw_mutex, options, versions, storage, &mut |_, writer| {
+        return copy_payload(reader, writer);
+    });
+}
 
-        // Critical_region_write drops the rwlock to the file but we also need to release the atomic reference counter file_lock regardless of write result
-        drop(file_lock);
+// Like write_locked, but the new content is produced by content, which gets the locked destination and the
+// temporary file to fill. Nothing is committed if content fails.
+fn write_locked_with(
+    file_path: PathBuf,
+    fsrw_mutex: &FsrwMutex,
+    options: WriteOptions,
+    versions: &VersionStore,
+    storage: &dyn StorageBackend,
+    content: &mut dyn FnMut(&Path, &mut dyn Write) -> io::Result<()>,
+) -> io::Result<Resolution> {
+    // Acquire write access to the file. The policy is applied while file_dict is locked, so no other session can
+    // lock the destination in between. Files other sessions are still uploading count as existing.
+    let WriteOptions {policy, source_modified, attributes, max_bytes} = options;
+    let mut write_path: PathBuf = PathBuf::new();
+    let modified = |path: &Path| storage.stat(path).ok().map(|metadata| metadata.modified);
+    let locked_path = fsrw_mutex.write_chosen(&[], |taken| match policy.resolve_with(&file_path, source_modified, taken, &modified) {
+        Resolution::Write(resolved_path) => {
+            write_path = resolved_path.clone();
+            Ok(resolved_path)
+        }
+        resolution => Err(resolution),
+    })?;
+    let locked_path = match locked_path {
+        Ok(locked_path) => locked_path,
+        Err(resolution) => return Ok(resolution),
+    };
 
-        // Update file_dict that file rwlock was unlocked
-        // Lock file dict
-        let file_dict = match fsrw_mutex.file_dict.lock() {
-            Ok(guard) => guard,
-            // Need to handle this properly
-            Err(poisoned) => {
-                panic!("file_dict poisoned: {}", poisoned)
+    // Write here, unless another session replaced the file with a newer one while we were waiting for the lock.
+    let existed: bool = storage.is_file(&locked_path);
+    // The file is unlocked when locked_path is dropped, whether writing works or not.
+    if policy == OverwritePolicy::Newer && existed && !is_newer_than(source_modified, modified(&locked_path)) {
+        return Ok(Resolution::Skip);
+    }
+    // A file that didn't exist before has no content to keep
+    let versions = if existed { Some(versions) } else { None };
+    let mut limited_content = |locked_path: &Path, writer: &mut dyn Write| -> io::Result<()> {
+        return content(locked_path, &mut LimitedWriter {inner: writer, written: 0, max_bytes});
+    };
+    critical_region_write(&mut limited_content, locked_path, attributes, versions, storage)?;
+    return Ok(Resolution::Write(write_path));
+}
+
+// Passes what is written on to inner until it would get more than max_bytes, then fails with TooLarge
+struct LimitedWriter<'a> {
+    inner: &'a mut dyn Write,
+    written: u64,
+    max_bytes: Option<u64>,
+}
+
+impl Write for LimitedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_bytes) = self.max_bytes {
+            if self.written + buf.len() as u64 > max_bytes {
+                return Err(io::Error::new(io::ErrorKind::Other, TooLarge {max_bytes}));
             }
-        };
-        release_file_rwlock(file_dict, file_path.to_path_buf());
+        }
+        let length = self.inner.write(buf)?;
+        self.written += length as u64;
+        return Ok(length);
+    }
 
-        return write_result;
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
     }
 }
 
 // This code holds the critical region (where rwlock<File> is held) for write so failing here can be handled by the caller safely
 fn critical_region_write(
-    payload_size: u64,
-    write_path: RwLockWriteGuard<PathBuf>,
-    tcpstream: &TcpStream,
+    content: &mut dyn FnMut(&Path, &mut dyn Write) -> io::Result<()>,
+    write_path: FileWriteGuard,
+    attributes: FileAttributes,
+    versions: Option<&VersionStore>,
+    storage: &dyn StorageBackend,
 ) -> io::Result<()> {
     println!("Exclusive write access obtained!");
-    let mut byte_count: u64 = 0;
-    let mut reader = BufReader::with_capacity(BUFFER_SIZE, tcpstream);
-    let capacity = reader.capacity() as u64;
-    let mut writer = File::create(write_path.clone())?;
-    while byte_count < payload_size {
-        // println!("Waiting for payload..");
-        if payload_size - byte_count < capacity {
-            let remaining_bytes = payload_size - byte_count;
-            let mut buffer = vec![0u8; (remaining_bytes).try_into().unwrap()];
-            reader.read_exact(&mut buffer)?;
-            // println!("Received bytes: {:?}",buffer);
-            // println!("About to write");
-            writer.write(&buffer)?;
-            byte_count += remaining_bytes;
-        } else {
-            let buffer = reader.fill_buf()?;
-            writer.write(buffer)?;
-            let length = buffer.len();
-            reader.consume(length);
-            byte_count += length as u64;
-            // print!("{}\t", byte_count);
-        };
+    // The new content only replaces the file once it is complete, so a failed transfer never leaves a partly
+    // written file behind
+    let mut writer = storage.open_write_atomic(&write_path)?;
+    content(&write_path, &mut writer)?;
+    writer.set_attributes(attributes);
+    if let Some(versions) = versions {
+        versions.save(&write_path)?;
     }
-    writer.flush()?;
+    writer.commit()?;
     println!("Done writing");
     drop(write_path);
     return Ok(());
 }
+
+fn copy_payload<R: Read>(reader: &mut R, writer: &mut dyn Write) -> io::Result<()> {
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let length = reader.read(&mut buffer)?;
+        if length == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..length])?;
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::server::storage::MemoryStorage;
+    use crate::server::usage::UsageLedger;
+
+    // A tar entry holding content, named without the checks of tar::Builder so that unsafe names can be written
+    fn append_entry(archive: &mut Vec<u8>, name: &str, content: &[u8]) {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(content);
+        archive.resize(archive.len().div_ceil(512) * 512, 0);
+    }
+
+    #[test]
+    fn extraction_rejects_entries_outside_of_the_directory() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.create_dir_all(Path::new("/home/d")).unwrap();
+        storage.create_dir_all(Path::new("/etc")).unwrap();
+        let ledger = Arc::new(UsageLedger::load(Path::new("/home"), Arc::clone(&storage)).unwrap());
+        let versions = VersionStore::new(Path::new("/home"), 0, Arc::clone(&storage), ledger);
+        let fsrw_mutex = FsrwMutex::new(Arc::clone(&storage));
+
+        let mut archive: Vec<u8> = vec![];
+        append_entry(&mut archive, "../x", b"outside");
+        append_entry(&mut archive, "/etc/x", b"absolute");
+        append_entry(&mut archive, "a/b", b"inside");
+        archive.extend_from_slice(&[0; 1024]);
+
+        let locked = |_: &Path| -> io::Result<bool> { return Ok(false) };
+        let options = ExtractOptions {locked: &locked, max_bytes: None, policy: OverwritePolicy::Overwrite};
+        let mut extracted = Extracted::default();
+        extract_archive(&mut archive.as_slice(), Path::new("/home/d"), &fsrw_mutex, &options, &versions, storage.as_ref(), &mut extracted).unwrap();
+
+        assert_eq!(extracted.rejected, vec!["../x".to_string(), "/etc/x".to_string()]);
+        assert_eq!(extracted.written, vec![PathBuf::from("/home/d/a/b")]);
+        assert!(!storage.exists(Path::new("/home/x")));
+        assert!(!storage.exists(Path::new("/etc/x")));
+        assert_eq!(storage.read_to_string(Path::new("/home/d/a/b")).unwrap(), "inside");
+    }
+}
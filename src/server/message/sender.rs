@@ -1,12 +1,15 @@
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
 use std::io::Write;
 use std::net::TcpStream;
-use std::path::PathBuf;
-use std::sync::RwLockReadGuard;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-use crate::message::{MessageKind, BUFFER_SIZE, HEADER_SIZE};
+use crate::message::{ChunkedWriter, FileAttributes, MessageKind, BUFFER_SIZE, CHUNKED_PAYLOAD, HEADER_SIZE};
 use crate::server::fsrw_mutex::*;
+use crate::server::sessions::{self, CountingWriter};
+use crate::server::storage::{EntryKind, StorageBackend, StorageEntry, StorageMetadata};
+use crate::server::utilities::{is_hidden, ERR_FILE_UNAVAILABLE};
+use crate::utilities::format_error;
 
 // DO NOT RELY ON MESSAGE SENDER TO VALIDATE FILEPATHS. ALL FILEPATHS ARE ASSUMED TO BE VALID.
 
@@ -16,6 +19,10 @@ pub struct MessageSender {
     pub command: MessageKind,
     pub arguments: String,
     pub file_path: Option<PathBuf>,
+    // Directory to be sent as a tar archive of unknown length
+    pub directory_path: Option<PathBuf>,
+    // Payload that is already in memory
+    pub payload: Option<Vec<u8>>,
     // pub writer: BufWriter<&'a TcpStream>
 }
 
@@ -27,77 +34,103 @@ impl MessageSender {
             command,
             arguments,
             file_path,
+            directory_path: None,
+            payload: None,
+        }
+    }
+
+    // generator for a message carrying payload
+    pub fn with_payload(command: MessageKind, arguments: String, payload: Vec<u8>) -> Self {
+        Self {
+            command,
+            arguments,
+            file_path: None,
+            directory_path: None,
+            payload: Some(payload),
+        }
+    }
+
+    // generator for a message carrying a tar archive of directory_path, generated while it is being sent
+    pub fn archive(command: MessageKind, arguments: String, directory_path: PathBuf) -> Self {
+        Self {
+            command,
+            arguments,
+            file_path: None,
+            directory_path: Some(directory_path),
+            payload: None,
         }
     }
 
     // Blocking function!!!
-    pub fn send_message(self, mut writer: &TcpStream, fsrw_mutex: &FsrwMutex) -> io::Result<()> {
+    pub fn send_message(self, mut writer: &TcpStream, fsrw_mutex: &FsrwMutex, storage: &dyn StorageBackend) -> io::Result<()> {
         // println!("Sent message called once");
         // Generate message and send headers
 
         // Send payload if any
         match &self.file_path {
             Some(file_path) => {
-                // Lock file_dict
-                let file_dict = match fsrw_mutex.file_dict.lock() {
-                    Ok(guard) => guard,
-                    // Need to handle this properly
-                    Err(poisoned) => {
-                        panic!("file_dict poisoned: {}", poisoned)
-                    }
-                };
-                let file_lock = acquire_file_rwlock(file_dict, file_path.to_path_buf());
-                // file_dict is automatically unlocked when acquire_file_rwlock consumes it
-
-                // Lock rwlock as a reader
-                let read_path = match file_lock.read() {
-                    Ok(guard) => guard,
-                    // Need to handle this properly
-                    Err(poisoned) => {
-                        panic!("file_dict poisoned: {}", poisoned)
+                // Lock the file as a reader. It is unlocked when read_path is dropped, whether sending works or not
+                let read_path = match fsrw_mutex.read(file_path.to_path_buf()) {
+                    Ok(read_path) => read_path,
+                    Err(e) => {
+                        // Nothing has been sent yet, so the client can still be told instead of waiting for the file
+                        println!("Cannot lock {:?}: {}", file_path, e);
+                        return unavailable(file_path, &e).send_message(writer, fsrw_mutex, storage);
                     }
                 };
 
                 // Send here
-                let send_result = self.critical_region_send(read_path, writer);
-                // critical_region_send drops the rwlock to the file but we also need to release the atomic reference counter file_lock regardless of write result
-                drop(file_lock);
-
-                // Update file_dict that file rwlock was unlocked
-                // Lock file dict
-                let file_dict = match fsrw_mutex.file_dict.lock() {
-                    Ok(guard) => guard,
-                    // Need to handle this properly
-                    Err(poisoned) => {
-                        panic!("file_dict poisoned: {}", poisoned)
-                    }
-                };
-                release_file_rwlock(file_dict, file_path.to_path_buf());
-
-                return send_result;
+                return self.critical_region_send(read_path, writer, storage);
             }
             None => {
-                let headers = self.generate_headers()?;
+                let headers = self.generate_headers(storage)?;
                 writer.write_all(&headers)?;
+                if let Some(directory_path) = &self.directory_path {
+                    if let Err(e) = send_archive(directory_path, writer, fsrw_mutex, storage) {
+                        // The archive was aborted, so the client reads why next
+                        println!("Cannot archive {:?}: {}", directory_path, e);
+                        return unavailable(directory_path, &e).send_message(writer, fsrw_mutex, storage);
+                    }
+                }
+                if let Some(payload) = &self.payload {
+                    writer.write_all(payload)?;
+                }
             }
         }
         writer.flush()?;
         return Ok(());
     }
 
-    fn generate_headers(&self) -> io::Result<Vec<u8>> {
+    fn generate_headers(&self, storage: &dyn StorageBackend) -> io::Result<Vec<u8>> {
         let mut payload_length: u64 = 0;
+        let mut arguments: String = self.arguments.clone();
         match &self.file_path {
             Some(file_path) => {
-                let file = File::open(file_path)?;
-                payload_length = file.metadata()?.len();
+                let details = storage.details(file_path)?;
+                payload_length = details.metadata.size;
+                // Lets the client decide whether its copy is newer, and keep the attributes if it wants to
+                let attributes = FileAttributes {
+                    modified: Some(details.metadata.modified),
+                    mode: details.mode,
+                };
+                attributes.add_to(&mut arguments);
+            }
+            None => {
+                if self.directory_path.is_some() {
+                    payload_length = CHUNKED_PAYLOAD;
+                }
+                if let Some(payload) = &self.payload {
+                    payload_length = payload.len() as u64;
+                }
             }
-            None => {}
         }
-        let argument_bytes = self.arguments.as_bytes();
+        let argument_bytes = arguments.as_bytes();
         let argument_length: u32 = argument_bytes.len().try_into().unwrap();
 
-        let size: u64 = HEADER_SIZE as u64 + argument_length as u64 + payload_length;
+        let size: u64 = match payload_length {
+            CHUNKED_PAYLOAD => CHUNKED_PAYLOAD,
+            _ => HEADER_SIZE as u64 + argument_length as u64 + payload_length,
+        };
         let mut headers: Vec<u8> = vec![];
 
         headers.extend(size.to_be_bytes());
@@ -109,15 +142,16 @@ impl MessageSender {
     // This code holds the critical region (where rwlock<File> is held) for write so failing here can be handled by the caller safely
     fn critical_region_send(
         &self,
-        read_path: RwLockReadGuard<PathBuf>,
+        read_path: FileReadGuard,
         mut writer: &TcpStream,
+        storage: &dyn StorageBackend,
     ) -> io::Result<()> {
         println!("Read access obtained!");
 
         // Generate headers. Note that this is done after holding the read lock for the file as writing to the file will affect file size and the headers generated will be invalid.
-        let headers = self.generate_headers()?;
+        let headers = self.generate_headers(storage)?;
         writer.write_all(&headers)?;
-        let mut file_reader = BufReader::with_capacity(BUFFER_SIZE, File::open(read_path.clone())?);
+        let mut file_reader = BufReader::with_capacity(BUFFER_SIZE, storage.open_read(&read_path)?);
 
         // Send file
         let mut length = 1;
@@ -126,6 +160,7 @@ impl MessageSender {
             // println!("File to be sent: {:?}",buffer);
             length = buffer.len();
             writer.write_all(&buffer)?;
+            sessions::count_transferred(length as u64);
             file_reader.consume(length);
         }
         drop(read_path);
@@ -133,3 +168,96 @@ impl MessageSender {
         return Ok(());
     }
 }
+
+// The Error reply for a path that couldn't be sent because of e
+fn unavailable(path: &Path, e: &io::Error) -> MessageSender {
+    let reply = match FileBusy::of(e) {
+        Some(busy) => busy.message(),
+        None => {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            format_error(ERR_FILE_UNAVAILABLE, &file_name)
+        }
+    };
+    return MessageSender::new(MessageKind::Error, reply, None);
+}
+
+// Streams directory_path as a tar archive in chunks. The archive is generated on the fly and each file is only
+// read locked while it is being added, so a large directory does not block writers for the whole transfer.
+// If a file can't be added the archive is aborted, and the error is returned for the caller to send.
+fn send_archive(
+    directory_path: &Path,
+    writer: &TcpStream,
+    fsrw_mutex: &FsrwMutex,
+    storage: &dyn StorageBackend,
+) -> io::Result<()> {
+    let root_name: PathBuf = match directory_path.file_name() {
+        Some(name) => PathBuf::from(name),
+        None => PathBuf::from("archive"),
+    };
+    let mut builder = tar::Builder::new(ChunkedWriter::new(CountingWriter::new(writer)));
+    if let Err(e) = append_directory(&mut builder, directory_path, &root_name, fsrw_mutex, storage) {
+        builder.into_inner()?.abort()?;
+        return Err(e);
+    }
+    let chunked_writer = builder.into_inner()?;
+    chunked_writer.finish()?;
+    println!("Done archiving");
+    return Ok(());
+}
+
+fn append_directory<W: Write>(
+    builder: &mut tar::Builder<W>,
+    directory_path: &Path,
+    archive_path: &Path,
+    fsrw_mutex: &FsrwMutex,
+    storage: &dyn StorageBackend,
+) -> io::Result<()> {
+    let metadata = storage.stat(directory_path)?;
+    let mut header = archive_header(tar::EntryType::Directory, 0o755, &metadata);
+    builder.append_data(&mut header, archive_path, io::empty())?;
+    let mut entries: Vec<StorageEntry> = storage
+        .list(directory_path)?
+        .into_iter()
+        .filter(|entry| !is_hidden(&entry.name))
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for entry in entries {
+        let path = directory_path.join(&entry.name);
+        let entry_archive_path = archive_path.join(&entry.name);
+        if entry.kind == EntryKind::Directory {
+            append_directory(builder, &path, &entry_archive_path, fsrw_mutex, storage)?;
+        } else if entry.kind == EntryKind::File {
+            // Lock the file as a reader while it is added
+            let read_path = fsrw_mutex.read(path)?;
+            append_file(builder, &read_path, &entry_archive_path, storage)?;
+        }
+        // Symlinks are skipped as they could point outside of the shared folder
+    }
+    return Ok(());
+}
+
+fn append_file<W: Write>(
+    builder: &mut tar::Builder<W>,
+    file_path: &Path,
+    archive_path: &Path,
+    storage: &dyn StorageBackend,
+) -> io::Result<()> {
+    let metadata = storage.stat(file_path)?;
+    let mut header = archive_header(tar::EntryType::Regular, 0o644, &metadata);
+    let reader = storage.open_read(file_path)?;
+    return builder.append_data(&mut header, archive_path, reader.take(metadata.size));
+}
+
+fn archive_header(entry_type: tar::EntryType, mode: u32, metadata: &StorageMetadata) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(match entry_type {
+        tar::EntryType::Regular => metadata.size,
+        _ => 0,
+    });
+    let modified = metadata.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    header.set_mtime(modified.as_secs());
+    return header;
+}
@@ -2,4 +2,14 @@ pub mod handler;
 pub mod utilities;
 pub mod fsrw_mutex;
 pub mod message;
-pub mod threadpool;
\ No newline at end of file
+pub mod threadpool;
+pub mod config;
+pub mod versions;
+pub mod trash;
+pub mod storage;
+pub mod watch;
+pub mod locks;
+pub mod users;
+pub mod usage;
+pub mod os_lock;
+pub mod sessions;
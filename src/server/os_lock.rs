@@ -0,0 +1,104 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::server::fsrw_mutex::FileBusy;
+use crate::server::utilities::lock_file_path_for;
+
+// How often a lock held by another process is tried again while waiting for it with a deadline
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// An OS advisory lock (flock) on a file, so that other server processes sharing the folder, and local programs that
+// flock the files, respect the transfers of this server and the other way around. It is released when dropped, as
+// closing the file releases it.
+// A file that doesn't exist yet can't be locked, so a lock file next to it is locked instead, which is removed again
+// when the lock is released. Paths are never locked through a file that other paths share, so sessions of this
+// process, which already hold the lock of the path in FsrwMutex, never wait for each other here.
+pub struct OsLock {
+    file: File,
+    // Set if file is the lock file of a path that didn't exist
+    lock_file: Option<PathBuf>,
+}
+
+impl OsLock {
+    // Blocks until path is locked, shared or exclusive, or fails with FileBusy once deadline has passed
+    pub fn lock(path: &Path, exclusive: bool, deadline: Option<Instant>) -> io::Result<OsLock> {
+        loop {
+            let (locked_path, is_lock_file) = lock_target(path);
+            let file = match is_lock_file {
+                true => OpenOptions::new().read(true).write(true).create(true).open(&locked_path)?,
+                false => File::open(&locked_path)?,
+            };
+            flock(&file, exclusive, deadline).map_err(|e| match e.kind() {
+                ErrorKind::WouldBlock => {
+                    let busy = FileBusy {path: path.to_path_buf(), writing: !exclusive};
+                    io::Error::new(ErrorKind::ResourceBusy, busy)
+                }
+                _ => e,
+            })?;
+
+            // An upload replaces a file by renaming a new one over it, and creates a file that didn't exist, and the
+            // lock file of a path is removed when its lock is released, so what was locked may not be the file at
+            // path anymore by the time the lock is taken. Then it is tried again.
+            let lock_file = if is_lock_file { Some(locked_path.clone()) } else { None };
+            if lock_target(path).0 == locked_path && is_same_file(&file, &locked_path) {
+                return Ok(OsLock {file, lock_file});
+            }
+            // The path was created in the meantime, so the lock file that is held is not needed anymore
+            drop(OsLock {file, lock_file});
+        }
+    }
+}
+
+impl Drop for OsLock {
+    // The lock file is removed while it is still locked, so whoever waits for it next sees that it is gone and
+    // locks a new one
+    fn drop(&mut self) {
+        if let Some(lock_file) = &self.lock_file {
+            if is_same_file(&self.file, lock_file) {
+                let _ = fs::remove_file(lock_file);
+            }
+        }
+    }
+}
+
+// The file to lock for path, and whether it is the lock file of a path that doesn't exist
+fn lock_target(path: &Path) -> (PathBuf, bool) {
+    if path.exists() {
+        return (path.to_path_buf(), false);
+    }
+    return (lock_file_path_for(path), true);
+}
+
+fn is_same_file(file: &File, path: &Path) -> bool {
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(locked), Ok(current)) => return locked.dev() == current.dev() && locked.ino() == current.ino(),
+        _ => return false,
+    }
+}
+
+// Fails with WouldBlock once deadline has passed
+fn flock(file: &File, exclusive: bool, deadline: Option<Instant>) -> io::Result<()> {
+    let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    loop {
+        let operation = if deadline.is_some() { operation | libc::LOCK_NB } else { operation };
+        // Safe as the file descriptor stays open for as long as file exists
+        let result = unsafe { libc::flock(file.as_raw_fd(), operation) };
+        if result == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            ErrorKind::Interrupted => continue,
+            ErrorKind::WouldBlock => match deadline {
+                Some(deadline) if Instant::now() < deadline => thread::sleep(RETRY_INTERVAL),
+                _ => return Err(e),
+            },
+            _ => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+thread_local! {
+    // The session run by this thread. Each session is run by a thread of its own for as long as it is connected.
+    static CURRENT: RefCell<Option<Arc<SessionState>>> = const { RefCell::new(None) };
+}
+
+// A file being sent or received by a session
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    // "up" or "down"
+    pub direction: &'static str,
+    pub name: String,
+    // None if the size isn't known up front, e.g. for an archive
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionDetails {
+    pub user: String,
+    pub directory: PathBuf,
+    pub transfer: Option<Transfer>,
+}
+
+// What a connected client is doing, for the sessions and locks commands
+pub struct SessionState {
+    pub id: u64,
+    pub address: String,
+    pub connected: SystemTime,
+    details: Mutex<SessionDetails>,
+    // Bytes of the current transfer sent or received so far
+    transferred: AtomicU64,
+}
+
+impl SessionState {
+    pub fn details(&self) -> SessionDetails {
+        return self.locked().clone();
+    }
+
+    pub fn user(&self) -> String {
+        return self.locked().user.clone();
+    }
+
+    pub fn transferred(&self) -> u64 {
+        return self.transferred.load(Ordering::Relaxed);
+    }
+
+    pub fn set_user(&self, user: &str) {
+        self.locked().user = user.to_string();
+    }
+
+    pub fn set_directory(&self, directory: &Path) {
+        self.locked().directory = directory.to_path_buf();
+    }
+
+    pub fn start_transfer(&self, direction: &'static str, name: &str, size: Option<u64>) {
+        self.transferred.store(0, Ordering::Relaxed);
+        self.locked().transfer = Some(Transfer {direction, name: name.to_string(), size});
+    }
+
+    pub fn end_transfer(&self) {
+        self.locked().transfer = None;
+    }
+
+    fn locked(&self) -> MutexGuard<'_, SessionDetails> {
+        match self.details.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                self.details.clear_poison();
+                poisoned.into_inner()
+            }
+        }
+    }
+}
+
+// The sessions of all connected clients
+pub struct Sessions {
+    sessions: Mutex<BTreeMap<u64, Arc<SessionState>>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        return Self {sessions: Mutex::new(BTreeMap::new())};
+    }
+
+    // Registers the session run by the calling thread. id must be unique among the connected sessions.
+    pub fn start(&self, id: u64, address: String, user: &str, directory: &Path) -> Arc<SessionState> {
+        let state = Arc::new(SessionState {
+            id,
+            address,
+            connected: SystemTime::now(),
+            details: Mutex::new(SessionDetails {user: user.to_string(), directory: directory.to_path_buf(), transfer: None}),
+            transferred: AtomicU64::new(0),
+        });
+        self.locked().insert(id, Arc::clone(&state));
+        CURRENT.with(|current| *current.borrow_mut() = Some(Arc::clone(&state)));
+        return state;
+    }
+
+    // Called by the thread that started the session once it is disconnected
+    pub fn end(&self, id: u64) {
+        self.locked().remove(&id);
+        CURRENT.with(|current| *current.borrow_mut() = None);
+    }
+
+    // Ordered by id
+    pub fn list(&self) -> Vec<Arc<SessionState>> {
+        return self.locked().values().cloned().collect();
+    }
+
+    fn locked(&self) -> MutexGuard<'_, BTreeMap<u64, Arc<SessionState>>> {
+        match self.sessions.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                self.sessions.clear_poison();
+                poisoned.into_inner()
+            }
+        }
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+// The session run by the calling thread, if any
+pub fn current() -> Option<Arc<SessionState>> {
+    return CURRENT.with(|current| current.borrow().clone());
+}
+
+// Adds bytes to the transfer of the session run by the calling thread
+pub fn count_transferred(bytes: u64) {
+    CURRENT.with(|current| {
+        if let Some(state) = current.borrow().as_ref() {
+            state.transferred.fetch_add(bytes, Ordering::Relaxed);
+        }
+    });
+}
+
+// Counts whatever is written through it as transferred
+pub struct CountingWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        return Self {inner};
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.inner.write(buf)?;
+        count_transferred(length as u64);
+        return Ok(length);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+// Counts whatever is read through it as transferred
+pub struct CountingReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        return Self {inner};
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = self.inner.read(buf)?;
+        count_transferred(length as u64);
+        return Ok(length);
+    }
+}
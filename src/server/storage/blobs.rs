@@ -0,0 +1,120 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::server::utilities::HIDDEN_PREFIX;
+
+// With deduplication, the content of every uploaded file is stored once in this folder inside the home folder,
+// named by its SHA-256. Files in the home folder are hard links to these blobs, so the link count of a blob is
+// its reference count. INODES_FOLDER maps the inode of each blob back to its hash, so that the blob of a file
+// can be found when the file is deleted or overwritten without hashing it again.
+const BLOBS_FOLDER: &str = "blobs";
+const INODES_FOLDER: &str = "inodes";
+
+// Blobs are added and released by all sessions
+static BLOBS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    blobs_directory: PathBuf,
+    enabled: bool,
+}
+
+impl BlobStore {
+    pub fn new(home_directory: &Path, enabled: bool) -> Self {
+        Self {
+            blobs_directory: home_directory.join(HIDDEN_PREFIX).join(BLOBS_FOLDER),
+            enabled,
+        }
+    }
+
+    // Whether the content of file_path is deduplicated. The server's own files in the hidden folder, e.g. the
+    // trash records, are written often and never shared, so they are left out.
+    pub fn covers(&self, file_path: &Path) -> bool {
+        return self.enabled && !file_path.starts_with(self.blobs_directory.parent().unwrap());
+    }
+
+    // Makes file_path, a complete file with content hash that isn't visible to anyone else yet, share its content
+    // with the blob of that hash. If there is no such blob yet, file_path becomes it.
+    pub fn store(&self, file_path: &Path, hash: &str) -> io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let blob_path = self.blobs_directory.join(hash);
+        let _blobs_lock = lock_blobs();
+        fs::create_dir_all(self.blobs_directory.join(INODES_FOLDER))?;
+        match fs::hard_link(file_path, &blob_path) {
+            Ok(()) => {
+                let inode = fs::metadata(&blob_path)?.ino();
+                fs::write(self.inode_path(inode), hash)?;
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                // Link to the blob under a name next to file_path first, so file_path is never missing
+                let link_path = PathBuf::from(format!("{}.blob", file_path.to_string_lossy()));
+                let _ = fs::remove_file(&link_path);
+                fs::hard_link(&blob_path, &link_path)?;
+                fs::rename(&link_path, file_path)?;
+                println!("Deduplicated {:?} to blob {}", file_path, hash);
+            }
+            Err(e) => return Err(e),
+        }
+        return Ok(());
+    }
+
+    // Called after a link to the content described by metadata was removed. Deletes the blob holding that
+    // content once nothing else refers to it.
+    pub fn release(&self, metadata: &fs::Metadata) -> io::Result<()> {
+        let inode_path = self.inode_path(metadata.ino());
+        let _blobs_lock = lock_blobs();
+        // Files stored while deduplication was off have no blob
+        let hash = match fs::read_to_string(&inode_path) {
+            Ok(hash) => hash,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let blob_path = self.blobs_directory.join(hash.trim());
+        let blob_metadata = fs::metadata(&blob_path)?;
+        if blob_metadata.ino() == metadata.ino() && blob_metadata.nlink() == 1 {
+            println!("Removing unreferenced blob {}", hash);
+            fs::remove_file(&blob_path)?;
+            fs::remove_file(&inode_path)?;
+        }
+        return Ok(());
+    }
+
+    // Deletes a file or directory like fs::remove_dir_all, releasing the blobs of the files in it
+    pub fn remove_all(&self, path: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.is_dir() {
+            for entry in fs::read_dir(path)? {
+                self.remove_all(&entry?.path())?;
+            }
+            fs::remove_dir(path)?;
+        } else {
+            fs::remove_file(path)?;
+            if metadata.is_file() {
+                self.release(&metadata)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn inode_path(&self, inode: u64) -> PathBuf {
+        return self.blobs_directory.join(INODES_FOLDER).join(inode.to_string());
+    }
+}
+
+// The lock guards no data, it only keeps changes to the blobs from interleaving, so it is taken again as is after a
+// thread panicked while holding it
+fn lock_blobs() -> MutexGuard<'static, ()> {
+    match BLOBS_LOCK.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            println!("Recovering the blobs lock after a thread panicked");
+            BLOBS_LOCK.clear_poison();
+            poisoned.into_inner()
+        }
+    }
+}
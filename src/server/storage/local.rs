@@ -0,0 +1,219 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::manifest::to_hex;
+use crate::message::FileAttributes;
+use crate::server::utilities::temp_path_for;
+
+use super::blobs::BlobStore;
+use super::{AtomicWrite, EntryKind, StorageBackend, StorageEntry, StorageDetails, StorageMetadata, StorageRead, StorageSpace};
+
+// Stores files in the local filesystem, optionally deduplicating their content in a BlobStore
+pub struct LocalStorage {
+    blobs: BlobStore,
+}
+
+impl LocalStorage {
+    pub fn new(home_directory: &Path, dedup: bool) -> Self {
+        Self {
+            blobs: BlobStore::new(home_directory, dedup),
+        }
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        let mut entries: Vec<StorageEntry> = vec![];
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            entries.push(StorageEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                kind: entry_kind(&entry.file_type()?),
+            });
+        }
+        return Ok(entries);
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<StorageMetadata> {
+        let metadata = fs::symlink_metadata(path)?;
+        return Ok(StorageMetadata {
+            kind: entry_kind(&metadata.file_type()),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn StorageRead>> {
+        return Ok(Box::new(File::open(path)?));
+    }
+
+    // The content goes to a temporary file next to path which is renamed over path on commit
+    fn open_write_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicWrite>> {
+        let temp_path = temp_path_for(path);
+        // A leftover temporary file may be a link to a blob, which must not be truncated
+        let _ = fs::remove_file(&temp_path);
+        let file = File::create(&temp_path)?;
+        let hasher = match self.blobs.covers(path) {
+            true => Some(Sha256::new()),
+            false => None,
+        };
+        return Ok(Box::new(LocalAtomicWrite {
+            file,
+            hasher,
+            temp_path,
+            path: path.to_path_buf(),
+            blobs: self.blobs.clone(),
+            attributes: FileAttributes::default(),
+            committed: false,
+        }));
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        return fs::create_dir(path);
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        return self.blobs.remove_all(path);
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        return fs::rename(from, to);
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        return path.canonicalize();
+    }
+
+    // A hard link keeps the content without copying it, as files are only ever replaced by a rename. With
+    // deduplication the copy is one more link to the same blob.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if fs::hard_link(from, to).is_err() {
+            fs::copy(from, to)?;
+        }
+        return Ok(());
+    }
+
+    fn append(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new().append(true).create(true).open(path)?;
+        return file.write_all(content);
+    }
+
+    fn details(&self, path: &Path) -> io::Result<StorageDetails> {
+        let metadata = fs::symlink_metadata(path)?;
+        let symlink_target = match metadata.file_type().is_symlink() {
+            true => Some(fs::read_link(path)?),
+            false => None,
+        };
+        return Ok(StorageDetails {
+            metadata: self.stat(path)?,
+            accessed: metadata.accessed().ok(),
+            changed: Some(UNIX_EPOCH + Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32)),
+            mode: Some(metadata.mode() & 0o7777),
+            owner: Some((metadata.uid(), metadata.gid())),
+            symlink_target,
+        });
+    }
+
+    fn space(&self, path: &Path) -> io::Result<Option<StorageSpace>> {
+        let c_path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(_) => return Err(io::Error::new(ErrorKind::InvalidInput, "Path contains a nul byte")),
+        };
+        let mut stats: MaybeUninit<libc::statvfs> = MaybeUninit::uninit();
+        // Safe because c_path is a valid C string and stats is only read after statvfs has filled it in
+        let stats = unsafe {
+            if libc::statvfs(c_path.as_ptr(), stats.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            stats.assume_init()
+        };
+        let block_size = stats.f_frsize as u64;
+        return Ok(Some(StorageSpace {
+            total: stats.f_blocks as u64 * block_size,
+            used: (stats.f_blocks - stats.f_bfree) as u64 * block_size,
+            available: stats.f_bavail as u64 * block_size,
+        }));
+    }
+}
+
+fn entry_kind(file_type: &fs::FileType) -> EntryKind {
+    if file_type.is_file() {
+        return EntryKind::File;
+    } else if file_type.is_dir() {
+        return EntryKind::Directory;
+    }
+    return EntryKind::Other;
+}
+
+// With deduplication, the content is hashed while it is written so that it can be swapped for a link to the
+// blob of the same content on commit. All links to a blob share its modification time and mode, so files with
+// attributes of their own only share a blob with files that have the same attributes.
+struct LocalAtomicWrite {
+    file: File,
+    hasher: Option<Sha256>,
+    temp_path: PathBuf,
+    path: PathBuf,
+    blobs: BlobStore,
+    attributes: FileAttributes,
+    committed: bool,
+}
+
+impl Write for LocalAtomicWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = self.file.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..length]);
+        }
+        return Ok(length);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.file.flush();
+    }
+}
+
+impl AtomicWrite for LocalAtomicWrite {
+    fn set_attributes(&mut self, attributes: FileAttributes) {
+        self.attributes = attributes;
+    }
+
+    fn commit(mut self: Box<Self>) -> io::Result<()> {
+        self.file.flush()?;
+        self.attributes.apply(&self.file)?;
+        if let Some(mut hasher) = self.hasher.take() {
+            if !self.attributes.is_empty() {
+                let mut attributes = String::new();
+                self.attributes.add_to(&mut attributes);
+                hasher.update(attributes.as_bytes());
+            }
+            self.blobs.store(&self.temp_path, &to_hex(&hasher.finalize()))?;
+        }
+        let replaced = fs::metadata(&self.path).ok();
+        fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        // Renaming does nothing if both names are links to the same blob already
+        if self.temp_path.exists() {
+            fs::remove_file(&self.temp_path)?;
+        }
+        if let Some(replaced) = replaced {
+            self.blobs.release(&replaced)?;
+        }
+        return Ok(());
+    }
+}
+
+impl Drop for LocalAtomicWrite {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use crate::message::FileAttributes;
+
+use super::{AtomicWrite, EntryKind, StorageBackend, StorageEntry, StorageMetadata, StorageRead};
+
+enum Node {
+    Directory { modified: SystemTime },
+    File { data: Arc<[u8]>, modified: SystemTime },
+}
+
+type Nodes = BTreeMap<PathBuf, Node>;
+
+// Keeps everything in memory, e.g. for tests. The root directory "/" always exists.
+pub struct MemoryStorage {
+    nodes: Arc<Mutex<Nodes>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        let mut nodes = Nodes::new();
+        nodes.insert(PathBuf::from("/"), Node::Directory { modified: SystemTime::now() });
+        Self {
+            nodes: Arc::new(Mutex::new(nodes)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Nodes> {
+        return lock_nodes(&self.nodes);
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>> {
+        let path = normalize(path);
+        let nodes = self.lock();
+        match nodes.get(&path) {
+            Some(Node::Directory { .. }) => {}
+            Some(Node::File { .. }) => return Err(io::Error::new(ErrorKind::Other, "Not a directory")),
+            None => return Err(not_found()),
+        }
+        let entries = nodes
+            .iter()
+            .filter(|(child, _)| child.parent() == Some(path.as_path()))
+            .map(|(child, node)| StorageEntry {
+                name: child.file_name().unwrap().to_string_lossy().to_string(),
+                kind: node_kind(node),
+            })
+            .collect();
+        return Ok(entries);
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<StorageMetadata> {
+        let nodes = self.lock();
+        match nodes.get(&normalize(path)) {
+            Some(Node::Directory { modified }) => Ok(StorageMetadata {
+                kind: EntryKind::Directory,
+                size: 0,
+                modified: *modified,
+            }),
+            Some(Node::File { data, modified }) => Ok(StorageMetadata {
+                kind: EntryKind::File,
+                size: data.len() as u64,
+                modified: *modified,
+            }),
+            None => Err(not_found()),
+        }
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn StorageRead>> {
+        let nodes = self.lock();
+        match nodes.get(&normalize(path)) {
+            Some(Node::File { data, .. }) => Ok(Box::new(Cursor::new(data.clone()))),
+            Some(Node::Directory { .. }) => Err(io::Error::new(ErrorKind::Other, "Is a directory")),
+            None => Err(not_found()),
+        }
+    }
+
+    fn open_write_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicWrite>> {
+        let path = normalize(path);
+        let nodes = self.lock();
+        check_parent(&nodes, &path)?;
+        if let Some(Node::Directory { .. }) = nodes.get(&path) {
+            return Err(io::Error::new(ErrorKind::Other, "Is a directory"));
+        }
+        return Ok(Box::new(MemoryAtomicWrite {
+            nodes: self.nodes.clone(),
+            path,
+            data: vec![],
+            modified: None,
+        }));
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut nodes = self.lock();
+        if nodes.contains_key(&path) {
+            return Err(io::Error::new(ErrorKind::AlreadyExists, "Entry exists"));
+        }
+        check_parent(&nodes, &path)?;
+        nodes.insert(path, Node::Directory { modified: SystemTime::now() });
+        return Ok(());
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        if path.parent().is_none() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Cannot remove the root directory"));
+        }
+        let mut nodes = self.lock();
+        if !nodes.contains_key(&path) {
+            return Err(not_found());
+        }
+        nodes.retain(|node_path, _| !node_path.starts_with(&path));
+        return Ok(());
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = normalize(from);
+        let to = normalize(to);
+        if from.parent().is_none() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Cannot move the root directory"));
+        }
+        let mut nodes = self.lock();
+        if !nodes.contains_key(&from) {
+            return Err(not_found());
+        }
+        check_parent(&nodes, &to)?;
+        if to.starts_with(&from) && to != from {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Cannot move a directory into itself"));
+        }
+        let moved: Vec<PathBuf> = nodes.keys().filter(|path| path.starts_with(&from)).cloned().collect();
+        nodes.retain(|path, _| !path.starts_with(&to));
+        for path in moved {
+            let node = nodes.remove(&path).unwrap();
+            let new_path = to.join(path.strip_prefix(&from).unwrap());
+            nodes.insert(new_path, node);
+        }
+        return Ok(());
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        if !self.lock().contains_key(&path) {
+            return Err(not_found());
+        }
+        return Ok(path);
+    }
+}
+
+struct MemoryAtomicWrite {
+    nodes: Arc<Mutex<Nodes>>,
+    path: PathBuf,
+    data: Vec<u8>,
+    // Permission bits aren't kept
+    modified: Option<SystemTime>,
+}
+
+impl Write for MemoryAtomicWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.data.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl AtomicWrite for MemoryAtomicWrite {
+    fn set_attributes(&mut self, attributes: FileAttributes) {
+        self.modified = attributes.modified;
+    }
+
+    fn commit(self: Box<Self>) -> io::Result<()> {
+        let mut nodes = lock_nodes(&self.nodes);
+        check_parent(&nodes, &self.path)?;
+        let node = Node::File {
+            data: self.data.into(),
+            modified: self.modified.unwrap_or_else(SystemTime::now),
+        };
+        nodes.insert(self.path, node);
+        return Ok(());
+    }
+}
+
+fn lock_nodes(nodes: &Mutex<Nodes>) -> MutexGuard<'_, Nodes> {
+    match nodes.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            println!("Recovering the memory storage after a thread panicked");
+            nodes.clear_poison();
+            poisoned.into_inner()
+        }
+    }
+}
+
+// Resolves "." and ".." without looking anything up. Relative paths are taken to start at the root.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    return normalized;
+}
+
+fn check_parent(nodes: &Nodes, path: &Path) -> io::Result<()> {
+    match path.parent().map(|parent| nodes.get(parent)) {
+        Some(Some(Node::Directory { .. })) => Ok(()),
+        _ => Err(not_found()),
+    }
+}
+
+fn node_kind(node: &Node) -> EntryKind {
+    match node {
+        Node::Directory { .. } => EntryKind::Directory,
+        Node::File { .. } => EntryKind::File,
+    }
+}
+
+fn not_found() -> io::Error {
+    return io::Error::new(ErrorKind::NotFound, "No such file or directory");
+}
@@ -0,0 +1,299 @@
+use std::io::{self, ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::message::FileAttributes;
+
+mod blobs;
+pub mod local;
+pub mod memory;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    // e.g. symlinks, which are never followed
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageMetadata {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl StorageMetadata {
+    pub fn is_file(&self) -> bool {
+        return self.kind == EntryKind::File;
+    }
+
+    pub fn is_dir(&self) -> bool {
+        return self.kind == EntryKind::Directory;
+    }
+}
+
+// Everything known about an entry, for showing it to users. Backends fill in what they keep track of.
+#[derive(Debug, Clone)]
+pub struct StorageDetails {
+    pub metadata: StorageMetadata,
+    pub accessed: Option<SystemTime>,
+    // When the metadata last changed
+    pub changed: Option<SystemTime>,
+    // Permission bits
+    pub mode: Option<u32>,
+    // User and group id
+    pub owner: Option<(u32, u32)>,
+    pub symlink_target: Option<PathBuf>,
+}
+
+// Space of the filesystem holding a path, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct StorageSpace {
+    pub total: u64,
+    pub used: u64,
+    // What can be written by the server, which may be less than what isn't used
+    pub available: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageEntry {
+    pub name: String,
+    pub kind: EntryKind,
+}
+
+// Files are read through this, so that they can be read from any offset, e.g. to apply a delta
+pub trait StorageRead: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> StorageRead for T {}
+
+// New content for a file. It only replaces the file once commit is called, dropping it before that leaves the
+// file as it was.
+pub trait AtomicWrite: Write + Send {
+    // Attributes the file gets on commit instead of the current time and default mode
+    fn set_attributes(&mut self, attributes: FileAttributes);
+    fn commit(self: Box<Self>) -> io::Result<()>;
+}
+
+// Everything the server does with the files it shares goes through a StorageBackend. Paths are absolute and
+// are not checked against the home folder, which is up to the caller.
+pub trait StorageBackend: Send + Sync {
+    // Entries of a directory, in no particular order
+    fn list(&self, path: &Path) -> io::Result<Vec<StorageEntry>>;
+    // Does not follow symlinks
+    fn stat(&self, path: &Path) -> io::Result<StorageMetadata>;
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn StorageRead>>;
+    // The parent directory of path must exist
+    fn open_write_atomic(&self, path: &Path) -> io::Result<Box<dyn AtomicWrite>>;
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+    // Removes a file, or a directory with everything in it
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    // Resolves "." and ".." (and symlinks where there are any). Fails if path doesn't exist.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    // Like stat, does not follow symlinks
+    fn details(&self, path: &Path) -> io::Result<StorageDetails> {
+        return Ok(StorageDetails {
+            metadata: self.stat(path)?,
+            accessed: None,
+            changed: None,
+            mode: None,
+            owner: None,
+            symlink_target: None,
+        });
+    }
+
+    // None for storage whose space isn't limited or can't be measured
+    fn space(&self, _path: &Path) -> io::Result<Option<StorageSpace>> {
+        return Ok(None);
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        return self.stat(path).is_ok();
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        return self.stat(path).map(|metadata| metadata.is_file()).unwrap_or(false);
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        return self.stat(path).map(|metadata| metadata.is_dir()).unwrap_or(false);
+    }
+
+    // path itself if it is a file, or the files anywhere below it if it is a directory. Empty if path doesn't exist.
+    fn files_below(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let metadata = match self.stat(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        if metadata.is_file() {
+            return Ok(vec![path.to_path_buf()]);
+        }
+        if !metadata.is_dir() {
+            return Ok(vec![]);
+        }
+        let files = self
+            .list_tree(path, &|_| false)?
+            .into_iter()
+            .filter(|(_, is_dir)| !is_dir)
+            .map(|(relative_path, _)| path.join(relative_path))
+            .collect();
+        return Ok(files);
+    }
+
+    // Lists the paths of all directories and files below root, relative to root, skipping names that skip returns
+    // true for. Symlinks are left out, they could point outside of the tree.
+    fn list_tree(&self, root: &Path, skip: &dyn Fn(&str) -> bool) -> io::Result<Vec<(PathBuf, bool)>> {
+        let mut tree: Vec<(PathBuf, bool)> = vec![];
+        let mut directories: Vec<PathBuf> = vec![PathBuf::new()];
+        while let Some(relative_dir) = directories.pop() {
+            for entry in self.list(&root.join(&relative_dir))? {
+                if skip(&entry.name) {
+                    continue;
+                }
+                let relative_path = relative_dir.join(&entry.name);
+                match entry.kind {
+                    EntryKind::Directory => {
+                        tree.push((relative_path.clone(), true));
+                        directories.push(relative_path);
+                    }
+                    EntryKind::File => tree.push((relative_path, false)),
+                    EntryKind::Other => {}
+                }
+            }
+        }
+        return Ok(tree);
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        let mut content = String::new();
+        self.open_read(path)?.read_to_string(&mut content)?;
+        return Ok(content);
+    }
+
+    // Replaces the content of path with content
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut writer = self.open_write_atomic(path)?;
+        writer.write_all(content)?;
+        return writer.commit();
+    }
+
+    // Adds content to the end of path, creating it if it doesn't exist
+    fn append(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut existing = match self.open_read(path) {
+            Ok(mut reader) => {
+                let mut existing = vec![];
+                reader.read_to_end(&mut existing)?;
+                existing
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        existing.extend_from_slice(content);
+        return self.write(path, &existing);
+    }
+
+    // Copies the file from to to, keeping its modification time
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let modified = self.stat(from)?.modified;
+        let mut reader = self.open_read(from)?;
+        let mut writer = self.open_write_atomic(to)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.set_attributes(FileAttributes { modified: Some(modified), mode: None });
+        return writer.commit();
+    }
+
+    // Creates path and any of its parents that are missing
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.is_dir(path) {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        match self.create_dir(path) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && self.is_dir(path) => return Ok(()),
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::SeekFrom;
+
+    // Every backend has to behave the same, so the same checks are run against each of them
+    fn check_backend(storage: &dyn StorageBackend, root: &Path) {
+        let dir = root.join("dir");
+        let file = dir.join("file.txt");
+
+        storage.create_dir(&dir).unwrap();
+        assert!(storage.is_dir(&dir));
+        assert_eq!(storage.create_dir(&dir).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert!(storage.create_dir(&root.join("missing/dir")).is_err());
+
+        // Nothing is visible until the write is committed
+        let mut writer = storage.open_write_atomic(&file).unwrap();
+        writer.write_all(b"hello world").unwrap();
+        assert!(!storage.exists(&file));
+        writer.commit().unwrap();
+        let metadata = storage.stat(&file).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.size, 11);
+
+        // A dropped write leaves the old content
+        let mut writer = storage.open_write_atomic(&file).unwrap();
+        writer.write_all(b"discarded").unwrap();
+        drop(writer);
+        let mut content = String::new();
+        storage.open_read(&file).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello world");
+
+        let mut reader = storage.open_read(&file).unwrap();
+        reader.seek(SeekFrom::Start(6)).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "world");
+
+        assert_eq!(
+            storage.list(&dir).unwrap(),
+            vec![StorageEntry { name: "file.txt".to_string(), kind: EntryKind::File }]
+        );
+        assert_eq!(storage.canonicalize(&dir.join("../dir/./file.txt")).unwrap(), storage.canonicalize(&file).unwrap());
+        assert!(storage.canonicalize(&dir.join("missing.txt")).is_err());
+
+        let moved = root.join("moved");
+        storage.rename(&dir, &moved).unwrap();
+        assert!(!storage.exists(&dir));
+        assert!(storage.is_file(&moved.join("file.txt")));
+
+        storage.create_dir_all(&moved.join("a/b")).unwrap();
+        assert!(storage.is_dir(&moved.join("a/b")));
+        storage.remove(&moved).unwrap();
+        assert!(!storage.exists(&moved.join("a/b")));
+        assert!(!storage.exists(&moved));
+        assert!(storage.list(root).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memory_storage() {
+        check_backend(&MemoryStorage::new(), Path::new("/"));
+    }
+
+    #[test]
+    fn local_storage() {
+        let root = std::env::temp_dir().join(format!("parfs-storage-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+        check_backend(&LocalStorage::new(&root, false), &root);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,43 +1,93 @@
-use std::sync::{Arc, Mutex, mpsc::{self, Sender, Receiver}};
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-type Job = Box<dyn FnOnce(usize) + Send + 'static>;
+type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// Runs jobs on at least min_workers and at most max_workers threads. Workers are spawned when a job comes in and
+// none is idle, and retire once they have been idle for idle_timeout while there are more than min_workers.
+// Jobs that come in while max_workers are busy wait in the queue, up to queue_length of them.
 pub struct ThreadPool{
-  workers: Vec<Worker>,
-  tx: Option<Sender<Job>>,
+  state: Arc<PoolState>,
 }
 
-impl ThreadPool {
-
-  // Initialises with number of threads to create
-  pub fn new(ports: Vec<usize>) -> ThreadPool {
+struct PoolState {
+  queue: Mutex<Queue>,
+  // Signalled when a job is queued or the pool is shutting down
+  available: Condvar,
+  min_workers: usize,
+  max_workers: usize,
+  idle_timeout: Duration,
+  queue_length: usize,
+}
 
-    let (tx, rx) = mpsc::channel();
+struct Queue {
+  jobs: VecDeque<Job>,
+  // Workers that are running, and those of them waiting for a job
+  workers: usize,
+  idle: usize,
+  // Numbers the workers for the logs
+  next_worker: usize,
+  // A retiring worker drops its own handle, so only the running workers are joined when the pool is dropped
+  handles: HashMap<usize, JoinHandle<()>>,
+  shutting_down: bool,
+}
 
-    // Only one instance of rx, will be shared between threads
-    let rx :Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(rx));
+impl ThreadPool {
 
-    // Initialise worker threads
-    let mut workers: Vec<Worker> = Vec::with_capacity(ports.len());
-    for port in ports{
-      println!("Creating worker for port {}...", port);
-      workers.push(Worker::new(port, rx.clone()));
+  // Initialises with the minimum number of workers
+  pub fn new(min_workers: usize, max_workers: usize, idle_timeout: Duration, queue_length: usize) -> ThreadPool {
+    let state = Arc::new(PoolState {
+      queue: Mutex::new(Queue {
+        jobs: VecDeque::new(),
+        workers: 0,
+        idle: 0,
+        next_worker: 1,
+        handles: HashMap::new(),
+        shutting_down: false,
+      }),
+      available: Condvar::new(),
+      min_workers,
+      max_workers: max_workers.max(1),
+      idle_timeout,
+      queue_length,
+    });
+
+    {
+      let mut queue = state.lock_queue();
+      for _ in 0..min_workers.min(state.max_workers) {
+        spawn_worker(&state, &mut queue);
+      }
     }
 
     ThreadPool{
-      workers,
-      tx: Some(tx),
+      state,
     }
   }
-   
-  // Adds given closure to queue, available workers will execute it
-  pub fn execute<F>(&self, f: F) 
-  where 
-    F: FnOnce(usize) -> () + Send + 'static
+
+  // Adds given closure to queue, an idle worker, or a new one if there is room for it, will execute it.
+  // If the queue is full the closure is handed back, along with the number of jobs waiting ahead of it.
+  pub fn execute<F>(&self, f: F) -> Result<(), (F, usize)>
+  where
+    F: FnOnce() -> () + Send + 'static
   {
+    let mut queue = self.state.lock_queue();
+    // Queued jobs that an idle worker is about to take don't wait, and neither does this one if a worker is left
+    let waiting = queue.jobs.len().saturating_sub(queue.idle);
+    let worker_left = queue.idle > queue.jobs.len() || queue.workers < self.state.max_workers;
+    if !worker_left && waiting >= self.state.queue_length {
+      return Err((f, waiting));
+    }
     let job: Job = Box::new(f);
-    self.tx.as_ref().unwrap().send(job).unwrap();
+    queue.jobs.push_back(job);
+    // Idle workers that were woken up for earlier jobs may not have taken them yet
+    if queue.idle < queue.jobs.len() && queue.workers < self.state.max_workers {
+      spawn_worker(&self.state, &mut queue);
+    }
+    self.state.available.notify_one();
+    Ok(())
   }
 
 }
@@ -45,45 +95,79 @@ impl ThreadPool {
 impl Drop for ThreadPool{
   fn drop(&mut self){
 
-    drop(self.tx.take());
+    // Workers finish the jobs that are queued before they terminate
+    let handles: Vec<(usize, JoinHandle<()>)> = {
+      let mut queue = self.state.lock_queue();
+      queue.shutting_down = true;
+      queue.handles.drain().collect()
+    };
+    self.state.available.notify_all();
+
+    for (number, handle) in handles{
+      println!("Shutting down worker {}...", number);
+      if handle.join().is_err() {
+        println!("Worker {} panicked", number);
+      }
+    }
+  }
+}
 
-    for worker in &mut self.workers{
-      println!("Shutting down worker with port {}...", worker.port);
-      
-      if let Some(handle) = worker.handle.take(){
-        handle.join().unwrap();
+impl PoolState {
+  fn lock_queue(&self) -> MutexGuard<'_, Queue> {
+    match self.queue.lock() {
+      Ok(guard) => guard,
+      Err(poisoned) => {
+        self.queue.clear_poison();
+        poisoned.into_inner()
       }
     }
   }
 }
 
-struct Worker{
-  port: usize,
-  handle: Option<JoinHandle<()>>
+fn spawn_worker(state: &Arc<PoolState>, queue: &mut Queue) {
+  let number = queue.next_worker;
+  queue.next_worker += 1;
+  queue.workers += 1;
+  println!("Creating worker {}...", number);
+  let worker_state = Arc::clone(state);
+  let handle: JoinHandle<()> = thread::spawn(move || run_worker(worker_state, number));
+  queue.handles.insert(number, handle);
 }
 
-impl Worker{
-  fn new(port: usize, rx: Arc<Mutex<Receiver<Job>>>) -> Worker {
-
-    // Loop waiting for task till Terminate signal comes in
-    let handle: JoinHandle<()> = thread::spawn(move || loop {
-        let job_result = rx.lock().unwrap().recv();
-
-        match job_result{
-          Ok(job) => {
-            println!("Worker for port {} has received instructions.", port);
-            job(port);
-          }
-          Err(_) => {
-            println!("Worker {} is terminating. Initiating self-destruct sequence...", port);
-            break;
-          }
-        }
-      });
-
-    Worker {
-      port, 
-      handle: Some(handle),
+// Loop waiting for jobs till the pool shuts down or the worker has been idle for long enough to retire
+fn run_worker(state: Arc<PoolState>, number: usize) {
+  let mut queue = state.lock_queue();
+  loop {
+    if let Some(job) = queue.jobs.pop_front() {
+      drop(queue);
+      println!("Worker {} has received instructions.", number);
+      // A job that panics only ends its own connection, the worker goes on with the next one
+      if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        println!("Worker {} recovered from a job that panicked", number);
+      }
+      queue = state.lock_queue();
+      continue;
+    }
+    if queue.shutting_down {
+      println!("Worker {} is terminating. Initiating self-destruct sequence...", number);
+      break;
+    }
+
+    queue.idle += 1;
+    let (guard, wait) = match state.available.wait_timeout(queue, state.idle_timeout) {
+      Ok(result) => result,
+      Err(poisoned) => {
+        state.queue.clear_poison();
+        poisoned.into_inner()
+      }
+    };
+    queue = guard;
+    queue.idle -= 1;
+    if wait.timed_out() && queue.jobs.is_empty() && !queue.shutting_down && queue.workers > state.min_workers {
+      println!("Worker {} is retiring after being idle", number);
+      queue.handles.remove(&number);
+      break;
     }
   }
-}
\ No newline at end of file
+  queue.workers -= 1;
+}
@@ -0,0 +1,206 @@
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::server::fsrw_mutex::{FsrwMutex, LockMode};
+use crate::server::storage::StorageBackend;
+use crate::server::usage::UsageLedger;
+use crate::server::utilities::HIDDEN_PREFIX;
+use crate::utilities::{decode_time, encode_time};
+
+// Deleted entries are moved into this folder inside the home folder. Every user has their own trash folder in it,
+// holding a folder per deleted entry named by an increasing id. That folder contains the entry itself as DATA_NAME
+// and a small INFO_NAME file recording where it came from and when it was deleted. Whatever is in the trash of a user
+// is recorded as theirs in the ledger, so that deleting files doesn't make room for more.
+const TRASH_FOLDER: &str = "trash";
+const DATA_NAME: &str = "data";
+const INFO_NAME: &str = "info";
+
+#[derive(Debug)]
+pub struct TrashEntry {
+    pub id: u32,
+    // Original path relative to the home folder
+    pub original_path: PathBuf,
+    pub deleted: SystemTime,
+}
+
+pub struct Trash {
+    home_directory: PathBuf,
+    storage: Arc<dyn StorageBackend>,
+    ledger: Arc<UsageLedger>,
+}
+
+impl Trash {
+    pub fn new(home_directory: &Path, storage: Arc<dyn StorageBackend>, ledger: Arc<UsageLedger>) -> Self {
+        let home_directory = match storage.canonicalize(home_directory) {
+            Ok(home_directory) => home_directory,
+            Err(_) => home_directory.to_path_buf(),
+        };
+        Self { home_directory, storage, ledger }
+    }
+
+    // Moves path, a file or directory inside the home folder, into the trash of user and returns its id. Waits for
+    // transfers of path, or of files inside of it, to finish before moving it away.
+    pub fn put(&self, user: &str, path: &Path, fsrw_mutex: &FsrwMutex) -> io::Result<u32> {
+        let original_path = match path.strip_prefix(&self.home_directory) {
+            Ok(original_path) => original_path.to_path_buf(),
+            Err(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{:?} is outside of the home folder", path),
+                ))
+            }
+        };
+        let user_dir = self.user_dir(user);
+        self.storage.create_dir_all(&user_dir)?;
+
+        // create_dir fails if the folder exists, which keeps ids unique even with several sessions of the same user
+        let mut id: u32 = self.list_ids(&user_dir)?.last().map(|id| id + 1).unwrap_or(1);
+        loop {
+            match self.storage.create_dir(&user_dir.join(id.to_string())) {
+                Ok(()) => break,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => id += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        let entry_dir = user_dir.join(id.to_string());
+        // The entry is listed once it has its info, which is only written while both ends of the move are locked
+        if let Err(e) = self.move_in(path, &original_path, &entry_dir, fsrw_mutex) {
+            let _ = self.storage.remove(&entry_dir);
+            return Err(e);
+        }
+        self.ledger.forget_all(path)?;
+        self.ledger.record_all(&entry_dir.join(DATA_NAME), user)?;
+        return Ok(id);
+    }
+
+    fn move_in(&self, path: &Path, original_path: &Path, entry_dir: &Path, fsrw_mutex: &FsrwMutex) -> io::Result<()> {
+        let data_path = entry_dir.join(DATA_NAME);
+        let _locks = fsrw_mutex.lock(&[
+            (path.to_path_buf(), LockMode::Exclusive),
+            (data_path.clone(), LockMode::Exclusive),
+        ])?;
+        let info = format!(
+            "path={}\ndeleted={}\n",
+            original_path.to_string_lossy(),
+            encode_time(SystemTime::now())
+        );
+        self.storage.write(&entry_dir.join(INFO_NAME), info.as_bytes())?;
+        return self.storage.rename(path, &data_path);
+    }
+
+    // Lists the trash of user, oldest deletion first
+    pub fn list(&self, user: &str) -> io::Result<Vec<TrashEntry>> {
+        let user_dir = self.user_dir(user);
+        if !self.storage.is_dir(&user_dir) {
+            return Ok(vec![]);
+        }
+        let mut entries: Vec<TrashEntry> = vec![];
+        for id in self.list_ids(&user_dir)? {
+            if let Some(entry) = self.read_entry(&user_dir, id) {
+                entries.push(entry);
+            }
+        }
+        return Ok(entries);
+    }
+
+    // Looks up an entry in the trash of user
+    pub fn get(&self, user: &str, id: u32) -> Option<TrashEntry> {
+        return self.read_entry(&self.user_dir(user), id);
+    }
+
+    // Where the file or directory of an entry is kept
+    pub fn data_path(&self, user: &str, id: u32) -> PathBuf {
+        return self.user_dir(user).join(id.to_string()).join(DATA_NAME);
+    }
+
+    // Moves an entry out of the trash to destination, which must not exist yet. The caller must hold the locks on
+    // both destination and the data_path of the entry.
+    pub fn restore(&self, user: &str, id: u32, destination: &Path) -> io::Result<()> {
+        let entry_dir = self.user_dir(user).join(id.to_string());
+        if let Some(parent) = destination.parent() {
+            self.storage.create_dir_all(parent)?;
+        }
+        self.storage.rename(&self.data_path(user, id), destination)?;
+        self.storage.remove(&entry_dir)?;
+        self.ledger.forget_all(&entry_dir)?;
+        return self.ledger.record_all(destination, user);
+    }
+
+    // Permanently deletes everything in the trash of user
+    pub fn empty(&self, user: &str) -> io::Result<()> {
+        let user_dir = self.user_dir(user);
+        if self.storage.is_dir(&user_dir) {
+            self.storage.remove(&user_dir)?;
+        }
+        return self.ledger.forget_all(&user_dir);
+    }
+
+    // Permanently deletes the entries of all users that were deleted longer than max_age ago
+    pub fn purge_expired(&self, max_age: Duration) -> io::Result<()> {
+        let trash_dir = self.home_directory.join(HIDDEN_PREFIX).join(TRASH_FOLDER);
+        if !self.storage.is_dir(&trash_dir) {
+            return Ok(());
+        }
+        for user_entry in self.storage.list(&trash_dir)? {
+            let user_dir = trash_dir.join(&user_entry.name);
+            for id in self.list_ids(&user_dir)? {
+                let entry = match self.read_entry(&user_dir, id) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let age = SystemTime::now().duration_since(entry.deleted).unwrap_or_default();
+                if age > max_age {
+                    println!("Purging {:?} from the trash", entry.original_path);
+                    self.storage.remove(&user_dir.join(id.to_string()))?;
+                    self.ledger.forget_all(&user_dir.join(id.to_string()))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Absolute path that an entry was deleted from
+    pub fn original_location(&self, entry: &TrashEntry) -> PathBuf {
+        return self.home_directory.join(&entry.original_path);
+    }
+
+    fn user_dir(&self, user: &str) -> PathBuf {
+        return self
+            .home_directory
+            .join(HIDDEN_PREFIX)
+            .join(TRASH_FOLDER)
+            .join(user);
+    }
+
+    fn read_entry(&self, user_dir: &Path, id: u32) -> Option<TrashEntry> {
+        let info = self.storage.read_to_string(&user_dir.join(id.to_string()).join(INFO_NAME)).ok()?;
+        let mut original_path: Option<PathBuf> = None;
+        let mut deleted: Option<SystemTime> = None;
+        for line in info.lines() {
+            match line.split_once('=') {
+                Some(("path", path)) => original_path = Some(PathBuf::from(path)),
+                Some(("deleted", time)) => deleted = decode_time(time),
+                _ => {}
+            }
+        }
+        return Some(TrashEntry {
+            id,
+            original_path: original_path?,
+            deleted: deleted?,
+        });
+    }
+
+    // Entry ids in a trash folder in increasing order
+    fn list_ids(&self, user_dir: &Path) -> io::Result<Vec<u32>> {
+        let mut ids: Vec<u32> = self
+            .storage
+            .list(user_dir)?
+            .iter()
+            .filter_map(|entry| entry.name.parse::<u32>().ok())
+            .collect();
+        ids.sort();
+        return Ok(ids);
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::server::storage::StorageBackend;
+use crate::server::users::{Quota, Usage};
+use crate::server::utilities::HIDDEN_PREFIX;
+
+// Changes to who owns which file are appended to this file inside the hidden folder, one line per change with the
+// path relative to the home folder: "user\tpath" when user became the owner of the file at path, and "\tpath" when
+// the files at and below path stopped counting, e.g. because they were deleted. It is compacted when the server
+// starts.
+const OWNERS_FILE: &str = "owners";
+const TRASH_FOLDER: &str = "trash";
+const DATA_NAME: &str = "data";
+
+// Keeps track of which user owns which file, so that their usage can be measured against their quota. A file is
+// owned by whoever uploaded it last, the previous versions of a file by whoever owned it when it was replaced, and
+// the entries in the trash of a user by that user. The total of every user is kept up to date as files are recorded
+// and forgotten, so measuring it doesn't look at any files. Files changed outside of the server are only noticed
+// when it starts.
+pub struct UsageLedger {
+    home_directory: PathBuf,
+    storage: Arc<dyn StorageBackend>,
+    files: Mutex<HashMap<PathBuf, OwnedFile>>,
+    // Locked after files when both are needed
+    accounts: Mutex<HashMap<String, Arc<Mutex<Account>>>>,
+}
+
+struct OwnedFile {
+    owner: String,
+    // Size of the file when it was recorded
+    bytes: u64,
+}
+
+// What a user stores, and what the uploads of their sessions that are in progress may still add to it
+#[derive(Default)]
+struct Account {
+    stored: Usage,
+    reserved: Usage,
+}
+
+impl Account {
+    // Whether storing usage on top of everything else fits in quota, with freed no longer stored
+    fn fits(&self, usage: Usage, freed: Usage, quota: Quota) -> bool {
+        let mut total = self.stored;
+        total.add(self.reserved);
+        total.add(usage);
+        total.subtract(freed);
+        let fits_bytes = quota.max_bytes.is_none_or(|max_bytes| total.bytes <= max_bytes);
+        let fits_files = quota.max_files.is_none_or(|max_files| total.files <= max_files);
+        return fits_bytes && fits_files;
+    }
+
+    fn in_use(&self) -> Usage {
+        let mut usage = self.stored;
+        usage.add(self.reserved);
+        return usage;
+    }
+}
+
+// Room an upload takes in the quota of its user until it is dropped, once the upload is recorded or has failed
+pub struct Reservation {
+    account: Arc<Mutex<Account>>,
+    usage: Usage,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> u64 {
+        return self.usage.bytes;
+    }
+
+    // Grows or shrinks the reservation to usage, e.g. once the actual size of the upload is known. Err holds what
+    // the user stores and has reserved if usage doesn't fit, in which case the reservation is left as it was.
+    pub fn resize(&mut self, usage: Usage, freed: Usage, quota: Quota) -> Result<(), Usage> {
+        let mut account = lock(&self.account);
+        account.reserved.subtract(self.usage);
+        if !account.fits(usage, freed, quota) {
+            account.reserved.add(self.usage);
+            return Err(account.in_use());
+        }
+        account.reserved.add(usage);
+        self.usage = usage;
+        return Ok(());
+    }
+
+    // Grows the reservation to all bytes that are left in quota, for an upload whose size isn't known up front, and
+    // returns how many that are. None if the bytes of the user aren't limited.
+    pub fn claim_room(&mut self, freed: Usage, quota: Quota) -> Option<u64> {
+        let max_bytes = quota.max_bytes?;
+        let mut account = lock(&self.account);
+        account.reserved.subtract(self.usage);
+        let mut used = account.in_use();
+        used.subtract(freed);
+        self.usage.bytes = max_bytes.saturating_sub(used.bytes);
+        account.reserved.add(self.usage);
+        return Some(self.usage.bytes);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        lock(&self.account).reserved.subtract(self.usage);
+    }
+}
+
+impl UsageLedger {
+    // Reads the owners and the sizes of their files, and counts everything in the trash of each user towards them
+    pub fn load(home_directory: &Path, storage: Arc<dyn StorageBackend>) -> io::Result<Self> {
+        let home_directory = match storage.canonicalize(home_directory) {
+            Ok(home_directory) => home_directory,
+            Err(_) => home_directory.to_path_buf(),
+        };
+        let mut owners: HashMap<PathBuf, String> = HashMap::new();
+        match storage.read_to_string(&owners_path(&home_directory)) {
+            Ok(content) => {
+                for line in content.lines() {
+                    match line.split_once('\t') {
+                        Some(("", path)) => {
+                            let path = home_directory.join(path);
+                            owners.retain(|file_path, _| !file_path.starts_with(&path));
+                        }
+                        Some((user, path)) => {
+                            owners.insert(home_directory.join(path), user.to_string());
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let trash_dir = home_directory.join(HIDDEN_PREFIX).join(TRASH_FOLDER);
+        if storage.is_dir(&trash_dir) {
+            for user_entry in storage.list(&trash_dir)? {
+                let user_dir = trash_dir.join(&user_entry.name);
+                for entry in storage.list(&user_dir)? {
+                    for file_path in storage.files_below(&user_dir.join(&entry.name).join(DATA_NAME))? {
+                        owners.insert(file_path, user_entry.name.clone());
+                    }
+                }
+            }
+        }
+
+        let ledger = Self {
+            home_directory,
+            storage,
+            files: Mutex::new(HashMap::new()),
+            accounts: Mutex::new(HashMap::new()),
+        };
+        {
+            let mut files = lock(&ledger.files);
+            for (file_path, owner) in owners {
+                if let Ok(metadata) = ledger.storage.stat(&file_path) {
+                    if metadata.is_file() {
+                        ledger.set(&mut files, file_path, owner, metadata.size);
+                    }
+                }
+            }
+            let mut content = String::new();
+            for (file_path, file) in files.iter() {
+                content.push_str(&ledger.owner_line(&file.owner, file_path));
+            }
+            let owners_path = owners_path(&ledger.home_directory);
+            ledger.storage.create_dir_all(owners_path.parent().unwrap())?;
+            ledger.storage.write(&owners_path, content.as_bytes())?;
+        }
+        return Ok(ledger);
+    }
+
+    pub fn owner(&self, file_path: &Path) -> Option<String> {
+        return lock(&self.files).get(file_path).map(|file| file.owner.clone());
+    }
+
+    // Records user as the owner of file_path, a canonicalized path
+    pub fn record(&self, file_path: &Path, user: &str) -> io::Result<()> {
+        let bytes = self.storage.stat(file_path)?.size;
+        let mut files = lock(&self.files);
+        self.set(&mut files, file_path.to_path_buf(), user.to_string(), bytes);
+        return self.append(&self.owner_line(user, file_path));
+    }
+
+    // Records whoever owns file_path as the owner of copy, e.g. a version of it, both canonicalized paths
+    pub fn record_copy(&self, file_path: &Path, copy: &Path) -> io::Result<()> {
+        let owner = match self.owner(file_path) {
+            Some(owner) => owner,
+            None => return Ok(()),
+        };
+        return self.record(copy, &owner);
+    }
+
+    // Takes note of the new size of file_path, e.g. after it was restored from a version, keeping its owner
+    pub fn refresh(&self, file_path: &Path) -> io::Result<()> {
+        return self.record_copy(file_path, file_path);
+    }
+
+    // Records user as the owner of the files at path and below it, e.g. a directory moved to their trash
+    pub fn record_all(&self, path: &Path, user: &str) -> io::Result<()> {
+        let mut sizes: Vec<(PathBuf, u64)> = vec![];
+        for file_path in self.storage.files_below(path)? {
+            sizes.push((file_path.clone(), self.storage.stat(&file_path)?.size));
+        }
+        let mut files = lock(&self.files);
+        let mut lines = String::new();
+        for (file_path, bytes) in sizes {
+            lines.push_str(&self.owner_line(user, &file_path));
+            self.set(&mut files, file_path, user.to_string(), bytes);
+        }
+        return self.append(&lines);
+    }
+
+    // Stops counting the files at path and below it, e.g. because they were deleted or moved
+    pub fn forget_all(&self, path: &Path) -> io::Result<()> {
+        let mut files = lock(&self.files);
+        let forgotten: Vec<PathBuf> = files.keys().filter(|file_path| file_path.starts_with(path)).cloned().collect();
+        if forgotten.is_empty() {
+            return Ok(());
+        }
+        for file_path in forgotten {
+            if let Some(file) = files.remove(&file_path) {
+                lock(&self.account(&file.owner)).stored.subtract(Usage {bytes: file.bytes, files: 1});
+            }
+        }
+        return self.append(&self.owner_line("", path));
+    }
+
+    // What the files user owns take up, their versions and their trash included
+    pub fn usage(&self, user: &str) -> Usage {
+        return lock(&self.account(user)).stored;
+    }
+
+    // Reserves usage in the quota of user for an upload, unless it doesn't fit on top of what they store and what
+    // the other uploads of their sessions have reserved. freed is what the upload frees, e.g. a file of theirs it
+    // replaces. Err holds what they store and have reserved.
+    pub fn reserve(&self, user: &str, usage: Usage, freed: Usage, quota: Quota) -> Result<Reservation, Usage> {
+        let account = self.account(user);
+        let mut reservation = Reservation {account, usage: Usage::default()};
+        reservation.resize(usage, freed, quota)?;
+        return Ok(reservation);
+    }
+
+    // Makes owner the owner of file_path, taking it off the total of its previous owner
+    fn set(&self, files: &mut HashMap<PathBuf, OwnedFile>, file_path: PathBuf, owner: String, bytes: u64) {
+        if let Some(previous) = files.get(&file_path) {
+            lock(&self.account(&previous.owner)).stored.subtract(Usage {bytes: previous.bytes, files: 1});
+        }
+        lock(&self.account(&owner)).stored.add(Usage {bytes, files: 1});
+        files.insert(file_path, OwnedFile {owner, bytes});
+    }
+
+    fn account(&self, user: &str) -> Arc<Mutex<Account>> {
+        let mut accounts = lock(&self.accounts);
+        return Arc::clone(accounts.entry(user.to_string()).or_default());
+    }
+
+    fn owner_line(&self, user: &str, path: &Path) -> String {
+        let relative_path = path.strip_prefix(&self.home_directory).unwrap_or(path);
+        return format!("{}\t{}\n", user, relative_path.to_string_lossy());
+    }
+
+    // Called with files locked, so that the lines are in the order of the changes
+    fn append(&self, lines: &str) -> io::Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        return self.storage.append(&owners_path(&self.home_directory), lines.as_bytes());
+    }
+}
+
+fn owners_path(home_directory: &Path) -> PathBuf {
+    return home_directory.join(HIDDEN_PREFIX).join(OWNERS_FILE);
+}
+
+// The owners and totals are only changed in single steps that leave them consistent, so they are used as they are
+// after a thread panicked while holding them
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => {
+            println!("Recovering the file owners after a thread panicked");
+            mutex.clear_poison();
+            poisoned.into_inner()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::MemoryStorage;
+
+    #[test]
+    fn reservations_of_sessions_add_up() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.create_dir_all(Path::new("/home/d")).unwrap();
+        let ledger = UsageLedger::load(Path::new("/home"), Arc::clone(&storage)).unwrap();
+        let quota = Quota { max_bytes: Some(100), max_files: None };
+        let none = Usage::default();
+
+        storage.write(Path::new("/home/d/a"), &[0; 30]).unwrap();
+        ledger.record(Path::new("/home/d/a"), "alice").unwrap();
+        assert_eq!(ledger.usage("alice").bytes, 30);
+
+        // Two uploads that fit on their own don't both fit until the first one is dropped
+        let first = ledger.reserve("alice", Usage { bytes: 50, files: 1 }, none, quota).unwrap();
+        let in_use = ledger.reserve("alice", Usage { bytes: 50, files: 1 }, none, quota).err().unwrap();
+        assert_eq!(in_use.bytes, 80);
+        assert!(ledger.reserve("bob", Usage { bytes: 50, files: 1 }, none, quota).is_ok());
+        drop(first);
+        let mut second = ledger.reserve("alice", Usage { bytes: 50, files: 1 }, none, quota).unwrap();
+        assert_eq!(second.claim_room(none, quota), Some(70));
+        drop(second);
+
+        // Moving and deleting files changes the totals, and the journal gives the same totals when reloaded
+        storage.write(Path::new("/home/d/b"), &[0; 20]).unwrap();
+        ledger.record(Path::new("/home/d/b"), "bob").unwrap();
+        ledger.forget_all(Path::new("/home/d/a")).unwrap();
+        assert_eq!(ledger.usage("alice").bytes, 0);
+        storage.remove(Path::new("/home/d/a")).unwrap();
+        let reloaded = UsageLedger::load(Path::new("/home"), Arc::clone(&storage)).unwrap();
+        assert_eq!(reloaded.usage("alice").files, 0);
+        assert_eq!(reloaded.usage("bob").bytes, 20);
+        assert_eq!(reloaded.owner(Path::new("/home/d/b")).as_deref(), Some("bob"));
+    }
+}
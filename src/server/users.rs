@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::server::utilities::parse_size;
+
+// Limits on what a user may store. None means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        return self.max_bytes.is_none() && self.max_files.is_none();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UserAccount {
+    pub name: String,
+    password: String,
+    pub quota: Quota,
+}
+
+// The users that can log in, read from the file given with --users. Each line holds a user:
+//   name password [max-bytes] [max-files]
+// Sizes can end in K, M or G, and "-" or a missing value means unlimited. Lines starting with # are ignored.
+// The passwords are stored as they are, so the file should only be readable by the server.
+// A user named "anonymous" sets the quota of sessions that haven't logged in.
+#[derive(Debug, Default)]
+pub struct Users {
+    accounts: HashMap<String, UserAccount>,
+}
+
+impl Users {
+    // No users, so nobody can log in and nothing is limited
+    pub fn none() -> Self {
+        return Self::default();
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut accounts: HashMap<String, UserAccount> = HashMap::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let account = match parse_account(line) {
+                Some(account) => account,
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid user on line {} of {:?}", number + 1, path),
+                    ))
+                }
+            };
+            accounts.insert(account.name.clone(), account);
+        }
+        return Ok(Self { accounts });
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        return !self.accounts.is_empty();
+    }
+
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<&UserAccount> {
+        return self.accounts.get(name).filter(|account| account.password == password);
+    }
+
+    pub fn quota(&self, name: &str) -> Quota {
+        return self.accounts.get(name).map(|account| account.quota).unwrap_or_default();
+    }
+}
+
+fn parse_account(line: &str) -> Option<UserAccount> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 2 || fields.len() > 4 {
+        return None;
+    }
+    let max_bytes = match fields.get(2) {
+        Some(size) => parse_limit(size, parse_size)?,
+        None => None,
+    };
+    let max_files = match fields.get(3) {
+        Some(count) => parse_limit(count, |count| count.parse().ok())?,
+        None => None,
+    };
+    return Some(UserAccount {
+        name: fields[0].to_string(),
+        password: fields[1].to_string(),
+        quota: Quota { max_bytes, max_files },
+    });
+}
+
+// Some(None) for "-", None if value is invalid
+fn parse_limit(value: &str, parse: fn(&str) -> Option<u64>) -> Option<Option<u64>> {
+    if value == "-" {
+        return Some(None);
+    }
+    return parse(value).map(Some);
+}
+
+// How much a user stores at the moment
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: Usage) {
+        self.bytes += other.bytes;
+        self.files += other.files;
+    }
+
+    pub fn subtract(&mut self, other: Usage) {
+        self.bytes = self.bytes.saturating_sub(other.bytes);
+        self.files = self.files.saturating_sub(other.files);
+    }
+}
+
+// e.g. "1048576 of 10485760 bytes, 3 of 100 files"
+pub struct QuotaReport {
+    pub usage: Usage,
+    pub quota: Quota,
+}
+
+impl fmt::Display for QuotaReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let limit = |limit: Option<u64>| match limit {
+            Some(limit) => limit.to_string(),
+            None => "unlimited".to_string(),
+        };
+        write!(
+            f,
+            "{} of {} bytes, {} of {} files",
+            self.usage.bytes,
+            limit(self.quota.max_bytes),
+            self.usage.files,
+            limit(self.quota.max_files)
+        )
+    }
+}
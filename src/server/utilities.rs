@@ -1,6 +1,122 @@
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
 pub const ERR_NO_PATH: &str = "Cannot access {}: no such file";
 pub const ERR_NO_DIR: &str = "Cannot access {}: no such directory";
 pub const ERR_FILE_EXISTS: &str = "File exists at {}: cannot create directory";
+pub const ERR_ARCHIVE_ENTRIES: &str = "Rejected archive entries: {}";
+pub const ERR_NOT_OVERWRITTEN: &str = "File exists at {}: not overwritten";
+pub const ERR_INVALID_POLICY: &str = "Unknown overwrite policy {}";
+pub const ERR_VERSIONS_DISABLED: &str = "File versioning is not enabled on this server";
+pub const ERR_NO_VERSION: &str = "Cannot restore version {}: no such version";
+pub const ERR_REMOVE_HOME: &str = "Cannot remove {}: it is the home folder or contains the current directory";
+pub const ERR_NO_TRASH_ENTRY: &str = "Cannot restore {}: no such entry in the trash";
+pub const ERR_TRASH_COMMAND: &str = "Unknown trash command {}";
+pub const ERR_LOCKED: &str = "Cannot change {}";
+pub const ERR_NOT_LOCKED: &str = "Cannot unlock {}: it is not locked";
+pub const ERR_LOCK_DURATION: &str = "Invalid lock duration {}: give a number of minutes up to 1440";
+pub const ERR_LOGIN_DISABLED: &str = "Logging in is not enabled on this server";
+pub const ERR_LOGIN: &str = "Cannot log in as {}: wrong user name or password";
+pub const ERR_QUOTA: &str = "Cannot upload {}: it would exceed your quota";
+pub const ERR_NO_SPACE: &str = "Cannot upload {}: not enough free space on the server";
+pub const ERR_TOO_LARGE: &str = "Cannot upload {}: it is larger than your quota and the free space on the server allow";
+pub const ERR_SPACE_UNKNOWN: &str = "The free space of this server's storage is not known";
+pub const ERR_NO_ENTRY: &str = "Cannot access {}: no such file or directory";
+pub const ERR_FILE_UNAVAILABLE: &str = "Cannot access {}: the file is unavailable, please try again";
+pub const ERR_FILE_BEING_WRITTEN: &str = "Cannot access {}: it is busy, being written by another session";
+pub const ERR_FILE_BEING_READ: &str = "Cannot change {}: it is busy, being read by another session";
+pub const ERR_NOT_ADMIN: &str = "Only administrators can use {}";
+pub const ERR_SERVER_BUSY: &str = "The server is busy, {} clients are waiting ahead of you. Please try again later";
+pub const ERR_UNEXPECTED_MESSAGE: &str = "Unexpected {} message from the client, the request was cancelled";
+pub const ERR_BASIS_CHANGED: &str = "{} was changed by someone else during the transfer, please try again";
+
+pub const MSG_SKIPPED: &str = "Skipped {}: file already exists";
+pub const MSG_RENAMED: &str = "File exists: saved as {}";
+pub const MSG_NO_VERSIONS: &str = "No previous versions of {}";
+pub const MSG_TRASHED: &str = "Moved {} to the trash";
+pub const MSG_TRASH_EMPTY: &str = "The trash is empty";
+pub const MSG_RESTORED: &str = "Restored to {}";
+pub const MSG_LOCKED: &str = "Locked {}";
+pub const MSG_UNLOCKED: &str = "Unlocked {}";
+pub const MSG_LOGGED_IN: &str = "Logged in as {}";
+pub const MSG_NO_LOCKS: &str = "No files are in use";
+
+// How long a worker above the minimum waits for a new connection before it retires
+pub const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// How often the server looks for trash entries to purge
+pub const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How often watched directories are checked for changes made outside of the server
+pub const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long advisory locks last unless the client asks for a duration, and the longest duration it can ask for
+pub const DEFAULT_LOCK_DURATION: Duration = Duration::from_secs(30 * 60);
+pub const MAX_LOCK_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// User of sessions that haven't logged in
+pub const ANONYMOUS_USER: &str = "anonymous";
+
+// Names starting with this prefix belong to the server itself and are hidden from clients
+pub const HIDDEN_PREFIX: &str = ".parfs";
+
+// Uploads are written to this path next to the destination first and only renamed over it when complete
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap().to_str().unwrap();
+    return path.with_file_name(format!("{}-part-{}", HIDDEN_PREFIX, file_name));
+}
+
+// A path that doesn't exist yet is OS locked through this file next to it, see OsLock
+pub fn lock_file_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    return path.with_file_name(format!("{}-lock-{}", HIDDEN_PREFIX, file_name));
+}
+
+// e.g. "1500", "64K", "10M" or "2G"
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (number, unit): (&str, u64) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 1 << 10),
+        'M' => (&size[..size.len() - 1], 1 << 20),
+        'G' => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    return number.parse::<u64>().ok()?.checked_mul(unit);
+}
+
+pub fn is_hidden(name: &str) -> bool {
+    return name.starts_with(HIDDEN_PREFIX);
+}
+
+// Checks a path received from a client that will be joined onto a directory, e.g. a tar entry name.
+// Only plain relative paths are allowed: no root, no "..", and nothing belonging to the server.
+pub fn is_safe_relative_path(path: &Path) -> bool {
+    let mut has_name = false;
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                if is_hidden(&name.to_string_lossy()) {
+                    return false;
+                }
+                has_name = true;
+            }
+            Component::CurDir => {}
+            _ => return false,
+        }
+    }
+    return has_name;
+}
 
-pub const MAX_REQUEST_NO: usize = 10;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn only_plain_relative_paths_are_safe() {
+        let safe = ["a", "a/b.txt", "./a", "a/./b", "a/.hidden", "a/parfs"];
+        let unsafe_paths = ["", ".", "..", "../a", "a/../b", "a/..", "/a", "/etc/passwd", ".parfs/owners", "a/.parfs-part-b"];
+        for path in safe {
+            assert!(is_safe_relative_path(Path::new(path)), "{} should be safe", path);
+        }
+        for path in unsafe_paths {
+            assert!(!is_safe_relative_path(Path::new(path)), "{} should not be safe", path);
+        }
+    }
+}
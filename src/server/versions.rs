@@ -0,0 +1,161 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::server::storage::StorageBackend;
+use crate::server::usage::UsageLedger;
+use crate::server::utilities::HIDDEN_PREFIX;
+
+// Previous versions of files are kept in this folder inside the home folder, mirroring the layout of the home
+// folder. The versions of a file are named by increasing version number in a folder VERSIONS_CHILD below the path of
+// the file, which no file of a client can be named, so that e.g. the versions of "a" and those of a later "a/1" don't
+// get mixed up.
+const VERSIONS_FOLDER: &str = "versions";
+const VERSIONS_CHILD: &str = ".parfs-versions";
+
+#[derive(Debug)]
+pub struct Version {
+    pub id: u32,
+    pub modified: SystemTime,
+    pub size: u64,
+}
+
+// Keeps the previous versions of overwritten files
+pub struct VersionStore {
+    home_directory: PathBuf,
+    max_versions: usize,
+    storage: Arc<dyn StorageBackend>,
+    // Versions count towards the quota of whoever owned the file they were taken of
+    ledger: Arc<UsageLedger>,
+}
+
+impl VersionStore {
+    pub fn new(home_directory: &Path, max_versions: usize, storage: Arc<dyn StorageBackend>, ledger: Arc<UsageLedger>) -> Self {
+        let home_directory = match storage.canonicalize(home_directory) {
+            Ok(home_directory) => home_directory,
+            Err(_) => home_directory.to_path_buf(),
+        };
+        Self {
+            home_directory,
+            max_versions,
+            storage,
+            ledger,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        return self.max_versions > 0;
+    }
+
+    // Keeps the current content of file_path as its newest version and drops the oldest ones beyond the limit.
+    // The caller must hold the write lock on file_path.
+    pub fn save(&self, file_path: &Path) -> io::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let versions_dir = self.versions_dir(file_path)?;
+        self.storage.create_dir_all(&versions_dir)?;
+        let ids = self.list_ids(&versions_dir)?;
+        let next_id: u32 = ids.last().map(|id| id + 1).unwrap_or(1);
+        let version_path = versions_dir.join(next_id.to_string());
+
+        self.storage.copy(file_path, &version_path)?;
+        self.ledger.record_copy(file_path, &version_path)?;
+
+        let kept = ids.len() + 1;
+        if kept > self.max_versions {
+            for id in ids.iter().take(kept - self.max_versions) {
+                self.storage.remove(&versions_dir.join(id.to_string()))?;
+                self.ledger.forget_all(&versions_dir.join(id.to_string()))?;
+            }
+        }
+        println!("Saved version {} of {:?}", next_id, file_path);
+        return Ok(());
+    }
+
+    // Lists the versions of file_path from oldest to newest
+    pub fn list(&self, file_path: &Path) -> io::Result<Vec<Version>> {
+        let versions_dir = self.versions_dir(file_path)?;
+        if !self.storage.is_dir(&versions_dir) {
+            return Ok(vec![]);
+        }
+        let mut versions: Vec<Version> = vec![];
+        for id in self.list_ids(&versions_dir)? {
+            let metadata = self.storage.stat(&versions_dir.join(id.to_string()))?;
+            versions.push(Version {
+                id,
+                modified: metadata.modified,
+                size: metadata.size,
+            });
+        }
+        return Ok(versions);
+    }
+
+    // Path holding version id of file_path, if it exists
+    pub fn version_path(&self, file_path: &Path, id: u32) -> io::Result<Option<PathBuf>> {
+        let version_path = self.versions_dir(file_path)?.join(id.to_string());
+        if self.storage.is_file(&version_path) {
+            return Ok(Some(version_path));
+        }
+        return Ok(None);
+    }
+
+    fn versions_dir(&self, file_path: &Path) -> io::Result<PathBuf> {
+        let relative_path = match file_path.strip_prefix(&self.home_directory) {
+            Ok(relative_path) => relative_path,
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is outside of the home folder", file_path),
+                ))
+            }
+        };
+        return Ok(self
+            .home_directory
+            .join(HIDDEN_PREFIX)
+            .join(VERSIONS_FOLDER)
+            .join(relative_path)
+            .join(VERSIONS_CHILD));
+    }
+
+    // Version numbers in versions_dir in increasing order
+    fn list_ids(&self, versions_dir: &Path) -> io::Result<Vec<u32>> {
+        let mut ids: Vec<u32> = self
+            .storage
+            .list(versions_dir)?
+            .iter()
+            .filter_map(|entry| entry.name.parse::<u32>().ok())
+            .collect();
+        ids.sort();
+        return Ok(ids);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::MemoryStorage;
+
+    #[test]
+    fn versions_of_a_file_and_of_a_later_file_below_its_path_stay_apart() {
+        let storage: Arc<dyn StorageBackend> = Arc::new(MemoryStorage::new());
+        storage.create_dir_all(Path::new("/home")).unwrap();
+        let ledger = Arc::new(UsageLedger::load(Path::new("/home"), Arc::clone(&storage)).unwrap());
+        let versions = VersionStore::new(Path::new("/home"), 1, Arc::clone(&storage), ledger);
+
+        storage.write(Path::new("/home/a"), b"first").unwrap();
+        versions.save(Path::new("/home/a")).unwrap();
+        storage.remove(Path::new("/home/a")).unwrap();
+        storage.create_dir(Path::new("/home/a")).unwrap();
+        storage.write(Path::new("/home/a/1"), b"second").unwrap();
+        versions.save(Path::new("/home/a/1")).unwrap();
+        versions.save(Path::new("/home/a/1")).unwrap();
+
+        let kept = versions.list(Path::new("/home/a")).unwrap();
+        assert_eq!(kept.iter().map(|version| version.id).collect::<Vec<u32>>(), vec![1]);
+        assert_eq!(kept[0].size, 5);
+        let kept = versions.list(Path::new("/home/a/1")).unwrap();
+        assert_eq!(kept.iter().map(|version| version.id).collect::<Vec<u32>>(), vec![2]);
+    }
+}
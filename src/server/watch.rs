@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use crate::server::storage::{EntryKind, StorageBackend};
+use crate::server::utilities::is_hidden;
+
+// Counts the changes sessions made to the shared files, so that sessions watching a directory notice them right
+// away instead of at their next poll
+pub struct ChangeNotifier {
+    changes: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl ChangeNotifier {
+    pub fn new() -> Self {
+        Self {
+            changes: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn notify(&self) {
+        let mut changes = self.lock_changes();
+        *changes += 1;
+        self.changed.notify_all();
+    }
+
+    // Waits until there were more than seen changes or until timeout has passed, and returns the number of changes
+    pub fn wait(&self, seen: u64, timeout: Duration) -> u64 {
+        let changes = self.lock_changes();
+        let (changes, _) = match self.changed.wait_timeout_while(changes, timeout, |changes| *changes <= seen) {
+            Ok(result) => result,
+            Err(poisoned) => {
+                self.changes.clear_poison();
+                poisoned.into_inner()
+            }
+        };
+        return *changes;
+    }
+
+    // The counter is always valid, so it is used as is after a thread panicked
+    fn lock_changes(&self) -> MutexGuard<'_, u64> {
+        match self.changes.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                self.changes.clear_poison();
+                poisoned.into_inner()
+            }
+        }
+    }
+}
+
+impl Default for ChangeNotifier {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    Created(String),
+    Modified(String),
+    Deleted(String),
+    // From, to
+    Renamed(String, String),
+}
+
+// Sent to the client as "event\tname", or "renamed\tfrom\tto"
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchEvent::Created(name) => write!(f, "created\t{}", name),
+            WatchEvent::Modified(name) => write!(f, "modified\t{}", name),
+            WatchEvent::Deleted(name) => write!(f, "deleted\t{}", name),
+            WatchEvent::Renamed(from, to) => write!(f, "renamed\t{}\t{}", from, to),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EntryState {
+    kind: EntryKind,
+    size: u64,
+    modified: SystemTime,
+}
+
+// The entries of a directory at some point in time, by name. Changes are found by comparing two snapshots, which
+// covers changes made outside of the server as well.
+#[derive(Debug, Default)]
+pub struct DirectorySnapshot {
+    entries: BTreeMap<String, EntryState>,
+}
+
+impl DirectorySnapshot {
+    // A directory that can't be read, e.g. because it was deleted, is empty
+    pub fn take(storage: &dyn StorageBackend, dir_path: &Path) -> Self {
+        let mut entries: BTreeMap<String, EntryState> = BTreeMap::new();
+        for entry in storage.list(dir_path).unwrap_or_default() {
+            if is_hidden(&entry.name) {
+                continue;
+            }
+            if let Ok(metadata) = storage.stat(&dir_path.join(&entry.name)) {
+                let state = EntryState {
+                    kind: metadata.kind,
+                    size: metadata.size,
+                    modified: metadata.modified,
+                };
+                entries.insert(entry.name, state);
+            }
+        }
+        return Self { entries };
+    }
+
+    // What happened between self and newer. An entry that disappeared while one of the same kind, size and
+    // modification time appeared is taken to be renamed.
+    pub fn changes(&self, newer: &DirectorySnapshot) -> Vec<WatchEvent> {
+        let mut deleted: Vec<(&String, &EntryState)> = self
+            .entries
+            .iter()
+            .filter(|(name, _)| !newer.entries.contains_key(*name))
+            .collect();
+        let mut events: Vec<WatchEvent> = vec![];
+        for (name, state) in newer.entries.iter() {
+            match self.entries.get(name) {
+                Some(old_state) => {
+                    if old_state != state && state.kind != EntryKind::Directory {
+                        events.push(WatchEvent::Modified(name.clone()));
+                    }
+                }
+                None => match deleted.iter().position(|(_, old_state)| *old_state == state) {
+                    Some(index) => {
+                        let (old_name, _) = deleted.remove(index);
+                        events.push(WatchEvent::Renamed(old_name.clone(), name.clone()));
+                    }
+                    None => events.push(WatchEvent::Created(name.clone())),
+                },
+            }
+        }
+        for (name, _) in deleted {
+            events.push(WatchEvent::Deleted(name.clone()));
+        }
+        return events;
+    }
+}
@@ -1,3 +1,66 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 pub fn format_error(error: &str, object: &str) -> String {
     return error.replace("{}", object);
-}
\ No newline at end of file
+}
+
+// Times are sent over the wire as nanoseconds since the unix epoch
+pub fn encode_time(time: SystemTime) -> String {
+    let nanos: u128 = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_nanos(),
+        Err(_) => 0,
+    };
+    return nanos.to_string();
+}
+
+// None if value isn't a number or is too late to be represented as a SystemTime, as it comes from the other side
+pub fn decode_time(value: &str) -> Option<SystemTime> {
+    let nanos: u128 = value.parse().ok()?;
+    let seconds: u64 = (nanos / 1_000_000_000).try_into().ok()?;
+    let duration = Duration::new(seconds, (nanos % 1_000_000_000) as u32);
+    return UNIX_EPOCH.checked_add(duration);
+}
+
+// Formats a time as "YYYY-MM-DD HH:MM UTC" for display
+pub fn format_time(time: SystemTime) -> String {
+    let seconds: u64 = match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    };
+    let days = (seconds / 86400) as i64;
+    let minutes_of_day = (seconds % 86400) / 60;
+
+    // Converts days since the epoch to a calendar date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minutes_of_day / 60,
+        minutes_of_day % 60
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_time_rejects_times_that_do_not_fit() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123);
+        assert_eq!(decode_time(&encode_time(time)), Some(time));
+        assert_eq!(decode_time(&u128::MAX.to_string()), None);
+        assert_eq!(decode_time(&(u64::MAX as u128 * 1_000_000_000).to_string()), None);
+        assert_eq!(decode_time("soon"), None);
+    }
+}